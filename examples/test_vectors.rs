@@ -0,0 +1,21 @@
+//! Generates the known-answer test vectors and prints them as JSON.
+//! If a path is given, the test vectors stored there are verified instead.
+
+use zeppelin_core::test_vectors::Corpus;
+
+fn main() {
+    match std::env::args().nth(1) {
+        Some(path) => {
+            let json = std::fs::read_to_string(path).expect("Unable to read test vectors");
+            let corpus = Corpus::from_json(&json).unwrap();
+            match corpus.verify() {
+                Ok(()) => println!("All test vectors match"),
+                Err(e) => {
+                    eprintln!("{e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => println!("{}", Corpus::generate().unwrap().to_json()),
+    }
+}
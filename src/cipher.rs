@@ -2,21 +2,204 @@
 //! Additionally defines `CryptSettings` object that encapsulates all information
 //! required to perform cryptographic operations.
 
-use crate::hash::Balloon;
-use crate::progress::Progress;
-
+use crate::container::DecryptPolicy;
+use crate::error::{Error, Result};
+use crate::hash::{Balloon, BalloonHash, HashFunction, Indexing, Kmac256, Revision, Variant};
+use crate::kdf::{Argon2Settings, Kdf, KdfSettings};
+use crate::progress::{Progress, ProgressReader};
+use crate::secret::Key;
+
+use blake2::Blake2b512;
+use sha2::Sha512;
 use sha3::{Digest, Sha3_512};
+use subtle::ConstantTimeEq;
+use zeroize::{Zeroize, Zeroizing};
 
-use std::io::{self, Read, Seek, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::{Duration, Instant};
 
 use serde::{Deserialize, Serialize};
 
+mod chunked;
+mod pipeline;
+mod reader;
+
+pub use reader::DecryptReader;
+
+/// Layout of an encrypted stream and what its authentication covers.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum Format {
+    /// MAC covers key and plaintext only, the header is not authenticated.
+    Legacy,
+    /// MAC additionally covers the header. The stream is prefixed with a key
+    /// check and a header tag, so that a wrong password and a tampered header
+    /// can be told apart before the `Balloon` is created.
+    AuthenticatedHeader,
+    /// Separate keys for encryption and authentication are derived from the
+    /// password, the MAC covers header and ciphertext (encrypt-then-MAC).
+    /// Tampered data is rejected before the keystream is generated.
+    EncryptThenMac,
+    /// Like `EncryptThenMac`, but the salt of the `Balloon` is returned as is
+    /// instead of being wrapped with the ciphertext, so decryption reads the
+    /// ciphertext only once, see `decrypt_single_pass`. Data is decrypted before
+    /// its *MAC* has been verified and this is not an all-or-nothing transform.
+    SinglePass,
+    /// Like `SinglePass`, but the ciphertext is split into chunks of 1 MiB, each
+    /// followed by a tag bound to its index and whether it is the last one.
+    /// Every chunk is authenticated before it is decrypted. Requires
+    /// `KeystreamMode::Counter`, so that chunks can be decrypted on their own.
+    Chunked,
+}
+
+impl Format {
+    /// Format of streams created before it was recorded.
+    fn legacy() -> Self {
+        Self::Legacy
+    }
+}
+
+/// How the keystream is derived from the `Balloon` once it has been created.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum KeystreamMode {
+    /// Every block steps the `Balloon`, so the keystream can only be computed in order.
+    Sequential,
+    /// Block `i` is computed by `Balloon::block(i)`, so decryption can start
+    /// at any offset, see `DecryptReader`.
+    Counter,
+}
+
+impl KeystreamMode {
+    /// Mode of streams created before it was recorded.
+    fn legacy() -> Self {
+        Self::Sequential
+    }
+}
+
 /// Struct to encapsulate all parameters required for Balloon-Hashing.
 #[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
 pub struct CryptSettings {
-    pub s_cost: usize,
-    pub t_cost: usize,
-    pub step_delta: usize,
+    pub s_cost: u64,
+    pub t_cost: u64,
+    pub step_delta: u64,
+    /// Number of lanes of the `Balloon` filled in parallel.
+    #[serde(default = "single_lane")]
+    pub p_cost: u64,
+    #[serde(default = "Format::legacy")]
+    pub format: Format,
+    #[serde(default = "Revision::legacy")]
+    pub revision: Revision,
+    /// How the `Balloon` picks blocks to mix.
+    #[serde(default = "Indexing::legacy")]
+    pub indexing: Indexing,
+    /// Hash function the `Balloon` is built on, the *MAC* always uses `SHA3`.
+    #[serde(default = "HashFunction::legacy")]
+    pub hash: HashFunction,
+    /// How the keystream is derived from the `Balloon`.
+    #[serde(default = "KeystreamMode::legacy")]
+    pub mode: KeystreamMode,
+    /// Function deriving the key from the password.
+    #[serde(default = "KdfSettings::legacy")]
+    pub kdf: KdfSettings,
+    /// Whether a `Pepper` is required in addition to the password.
+    #[serde(default)]
+    pub peppered: bool,
+    /// Whether the data is encrypted with a random data key, which containers
+    /// store in `key.dat` wrapped by the key derived from the password, see
+    /// `container::change_password`. `encrypt` and `decrypt` then take the data
+    /// key instead of a password and skip the key derivation.
+    #[serde(default)]
+    pub wrapped_key: bool,
+    /// Preset these settings were created from, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<Preset>,
+}
+
+/// Named sets of `CryptSettings`. Once published the settings of a preset
+/// never change, stronger costs or newer formats are added as a new version instead.
+/// The v1 presets use `Format::EncryptThenMac`, `Revision::V2` and
+/// data-independent `Indexing`.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum Preset {
+    /// Balloon ~ 4 MB, Argon2 ~ 19 MB. For frequently opened containers.
+    #[serde(rename = "interactive-v1")]
+    InteractiveV1,
+    /// Balloon ~ 30 MB, Argon2 ~ 64 MB.
+    #[serde(rename = "moderate-v1")]
+    ModerateV1,
+    /// Balloon ~ 256 MB, Argon2 ~ 256 MB.
+    #[serde(rename = "sensitive-v1")]
+    SensitiveV1,
+    /// Balloon ~ 1 GB, Argon2 ~ 1 GB. For rarely opened containers,
+    /// just within the limits of `DecryptPolicy::default()`.
+    #[serde(rename = "archival-v1")]
+    ArchivalV1,
+}
+
+impl Preset {
+    /// All presets, from weakest to strongest.
+    pub const ALL: [Preset; 4] = [
+        Preset::InteractiveV1,
+        Preset::ModerateV1,
+        Preset::SensitiveV1,
+        Preset::ArchivalV1,
+    ];
+
+    /// Name the preset is serialized as.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Preset::InteractiveV1 => "interactive-v1",
+            Preset::ModerateV1 => "moderate-v1",
+            Preset::SensitiveV1 => "sensitive-v1",
+            Preset::ArchivalV1 => "archival-v1",
+        }
+    }
+
+    /// Settings of the preset.
+    pub fn settings(&self) -> CryptSettings {
+        // (s_cost, t_cost, step_delta, Argon2 m_cost in KiB, Argon2 t_cost)
+        let (s_cost, t_cost, step_delta, m_cost, kdf_t_cost) = match self {
+            Preset::InteractiveV1 => (1 << 16, 2, 3, 19 * 1024, 2),
+            Preset::ModerateV1 => (468750, 2, 3, 64 * 1024, 3),
+            Preset::SensitiveV1 => (1 << 22, 3, 3, 256 * 1024, 4),
+            Preset::ArchivalV1 => (1 << 24, 4, 4, 1024 * 1024, 4),
+        };
+        CryptSettings {
+            s_cost,
+            t_cost,
+            step_delta,
+            p_cost: 1,
+            format: Format::EncryptThenMac,
+            revision: Revision::V2,
+            indexing: Indexing::Independent,
+            hash: HashFunction::Sha3_512,
+            mode: KeystreamMode::Sequential,
+            kdf: KdfSettings::Argon2id(Argon2Settings {
+                m_cost,
+                t_cost: kdf_t_cost,
+                p_cost: 1,
+            }),
+            peppered: false,
+            wrapped_key: false,
+            preset: Some(*self),
+        }
+    }
+}
+
+impl std::fmt::Display for Preset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for Preset {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match Preset::ALL.into_iter().find(|preset| preset.name() == name) {
+            Some(preset) => Ok(preset),
+            None => Err(Error::UnknownPreset(name.to_string())),
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -27,8 +210,133 @@ impl CryptSettings {
             s_cost: 1000,
             t_cost: 2,
             step_delta: 3,
+            p_cost: 1,
+            format: Format::EncryptThenMac,
+            revision: Revision::latest(),
+            indexing: Indexing::Independent,
+            hash: HashFunction::Sha3_512,
+            mode: KeystreamMode::Sequential,
+            kdf: KdfSettings::Argon2id(Argon2Settings {
+                m_cost: 256,
+                t_cost: 2,
+                p_cost: 1,
+            }),
+            peppered: false,
+            wrapped_key: false,
+            preset: None,
+        }
+    }
+
+    /// Settings of the named `preset`, which is recorded along with them.
+    pub fn from_preset(preset: Preset) -> Self {
+        preset.settings()
+    }
+
+    /// Requires a `Pepper` in addition to the password, see `secret::Peppered`.
+    pub fn with_pepper(self) -> Self {
+        Self {
+            peppered: true,
+            ..self
+        }
+    }
+
+    /// Encrypts with a random data key wrapped by the password, see `wrapped_key`.
+    pub fn with_wrapped_key(self) -> Self {
+        Self {
+            wrapped_key: true,
+            ..self
         }
     }
+
+    /// Benchmarks `Balloon` and Argon2 on the current host and returns settings
+    /// for which creating the cipher takes about `target`, using at most
+    /// `max_memory` bytes for the `Balloon` and the key derivation each.
+    /// Half of `target` is spent in either of them.
+    ///
+    /// The costs are capped by `DecryptPolicy::default()`, so that the result
+    /// can always be decrypted with it, even if that is faster than `target`.
+    pub fn calibrate(target: Duration, max_memory: u64) -> Result<Calibration> {
+        let policy = DecryptPolicy::default();
+        let max_memory = max_memory.min(policy.max_memory);
+        let budget = target / 2;
+        let settings = Self {
+            preset: None,
+            ..Self::default()
+        };
+
+        // Time per step of the `Balloon`, measured on a small buffer with the
+        // variant that is returned. Filling it and one round take a step per block each.
+        let s_cost_bench = (max_memory / 64).clamp(1, 1 << 14);
+        let bench = Self {
+            s_cost: s_cost_bench,
+            t_cost: 1,
+            ..settings
+        };
+        let start = Instant::now();
+        let mut stream =
+            Stream::<Sha3_512>::new(b"calibration", vec![0; 64], bench, Progress::new())?;
+        let step_time = start.elapsed().as_secs_f64() / (2 * s_cost_bench) as f64;
+
+        // Prefer filling as much memory as allowed and mixing it once
+        let max_s_cost = (max_memory / 64).max(1) as f64;
+        let steps = (budget.as_secs_f64() / step_time).max(1.0);
+        let s_cost = (steps / 2.0).clamp(1.0, max_s_cost);
+        let t_cost = (steps / s_cost - 1.0).clamp(1.0, policy.max_t_cost as f64);
+
+        // Keystream is generated one 64 byte block per step
+        let blocks = s_cost_bench.max(1 << 12);
+        let start = Instant::now();
+        for _ in 0..blocks {
+            stream.balloon.step(Progress::new());
+        }
+        let throughput = (blocks * 64) as f64 / start.elapsed().as_secs_f64();
+
+        let mut kdf = calibrate_argon2(budget, max_memory)?;
+        kdf.t_cost = kdf.t_cost.min(policy.max_kdf_t_cost as u32);
+
+        let settings = Self {
+            s_cost: s_cost as u64,
+            t_cost: t_cost as u64,
+            kdf: KdfSettings::Argon2id(kdf),
+            ..settings
+        };
+        policy.check(&settings)?;
+        Ok(Calibration {
+            settings,
+            throughput,
+        })
+    }
+}
+
+/// Result of `CryptSettings::calibrate`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Calibration {
+    pub settings: CryptSettings,
+    /// Measured speed of the keystream in bytes per second.
+    pub throughput: f64,
+}
+
+/// Picks Argon2 settings for which the key derivation takes about `target`.
+fn calibrate_argon2(target: Duration, max_memory: u64) -> Result<Argon2Settings> {
+    let max_m_cost = (max_memory / 1024).min(u32::MAX as u64) as u32;
+    let mut settings = Argon2Settings {
+        m_cost: Argon2Settings::default().m_cost.min(max_m_cost).max(8),
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    let start = Instant::now();
+    settings.derive(b"calibration", &[0; 64], None, Progress::new())?;
+    let pass_time = start.elapsed().as_secs_f64();
+
+    let passes = target.as_secs_f64() / pass_time;
+    if passes >= 1.0 {
+        settings.t_cost = passes as u32;
+    } else {
+        // A single pass is too slow, use less memory instead
+        settings.m_cost = ((settings.m_cost as f64 * passes) as u32).max(8);
+    }
+    Ok(settings)
 }
 
 impl std::default::Default for CryptSettings {
@@ -38,68 +346,155 @@ impl std::default::Default for CryptSettings {
             s_cost: 468750,
             t_cost: 2,
             step_delta: 3,
+            p_cost: 1,
+            format: Format::EncryptThenMac,
+            revision: Revision::latest(),
+            indexing: Indexing::Independent,
+            hash: HashFunction::Sha3_512,
+            mode: KeystreamMode::Sequential,
+            kdf: KdfSettings::default(),
+            peppered: false,
+            wrapped_key: false,
+            preset: None,
         }
     }
 }
 
-/// Stream cipher like struct.
-/// Includes cache of stream.
-pub struct Stream {
-    balloon: Balloon,
+/// Stream cipher like struct, using a `Balloon` built on `D`.
+/// Includes cache of stream, which is wiped when dropped.
+pub struct Stream<D: BalloonHash = Sha3_512> {
+    balloon: Balloon<D>,
+    mode: KeystreamMode,
+    block: u64, // index of `mask` within the keystream
     mask: [u8; 64],
     mask_ptr: usize,
     salt_ptr: usize,
 }
 
-impl Stream {
-    /// Create a new stream cipher from `CryptSettings`
-    fn new(
+impl<D: BalloonHash> Drop for Stream<D> {
+    fn drop(&mut self) {
+        self.mask.zeroize();
+    }
+}
+
+impl<D: BalloonHash> Stream<D> {
+    /// Create a new stream cipher from `CryptSettings`, ignoring `settings.hash`.
+    pub(crate) fn new(
         passwd: impl AsRef<[u8]>,
         salt: Vec<u8>,
         settings: CryptSettings,
         prog: Progress,
-    ) -> Self {
-        let s_cost = settings.s_cost;
-        let t_cost = settings.t_cost;
-        let step_delta = settings.step_delta;
-
-        let mut balloon = Balloon::new(passwd, salt, s_cost, t_cost, step_delta, prog.clone());
-        let mask = balloon.step(prog);
-        Self {
+    ) -> Result<Self> {
+        let s_cost = to_usize(settings.s_cost, "s_cost")?;
+        let t_cost = to_usize(settings.t_cost, "t_cost")?;
+        let step_delta = to_usize(settings.step_delta, "step_delta")?;
+
+        let variant = Variant {
+            revision: settings.revision,
+            indexing: settings.indexing,
+            p_cost: to_usize(settings.p_cost, "p_cost")?,
+        };
+        let mut balloon = Balloon::with_hash(
+            passwd,
+            salt,
+            s_cost,
+            t_cost,
+            step_delta,
+            variant,
+            prog.clone(),
+        )?;
+        let mask = match settings.mode {
+            KeystreamMode::Sequential => balloon.step(prog),
+            KeystreamMode::Counter => balloon.block(0),
+        };
+        Ok(Self {
             balloon,
+            mode: settings.mode,
+            block: 0,
             mask,
             mask_ptr: 0,
             salt_ptr: 0,
+        })
+    }
+
+    /// Returns the next `len` bytes of the keystream.
+    pub(crate) fn keystream(&mut self, len: usize, prog: Progress) -> Vec<u8> {
+        let mut res = vec![0_u8; len];
+        self.apply_with_salt(&mut res, &mut [0_u8; 64], prog);
+        res
+    }
+
+    /// Applies the rest of the current mask to the start of `data`, stepping
+    /// to the next mask first if it is used up.
+    /// Returns the number of bytes processed, which is at most 64.
+    #[inline]
+    fn apply_block(&mut self, data: &mut [u8], prog: &Progress) -> usize {
+        if self.mask_ptr >= 64 {
+            self.block += 1;
+            self.mask = match self.mode {
+                KeystreamMode::Sequential => self.balloon.step(prog.clone()),
+                KeystreamMode::Counter => self.balloon.block(self.block),
+            };
+            self.mask_ptr = 0;
         }
+        let n = data.len().min(64 - self.mask_ptr);
+        xor_in_place(&mut data[..n], &self.mask[self.mask_ptr..self.mask_ptr + n]);
+        self.mask_ptr += n;
+        n
+    }
+
+    /// Moves to byte `offset` of the keystream. With `KeystreamMode::Sequential`
+    /// the keystream in between has to be computed and seeking backwards is unsupported.
+    fn seek(&mut self, offset: u64, prog: &Progress) -> io::Result<()> {
+        let pos = self.block * 64 + self.mask_ptr as u64;
+        match self.mode {
+            KeystreamMode::Counter => {
+                self.block = offset / 64;
+                self.mask = self.balloon.block(self.block);
+                self.mask_ptr = (offset % 64) as usize;
+            }
+            KeystreamMode::Sequential if offset >= pos => {
+                let mut skipped = Zeroizing::new([0_u8; 64]);
+                let mut remaining = offset - pos;
+                while remaining > 0 {
+                    let len = remaining.min(64) as usize;
+                    remaining -= self.apply_block(&mut skipped[..len], prog) as u64;
+                }
+            }
+            KeystreamMode::Sequential => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Seeking backwards requires `KeystreamMode::Counter`",
+                ))
+            }
+        }
+        self.salt_ptr = offset as usize;
+        Ok(())
     }
 
     /// Applies stream cipher to `data`, dynamically updating internal mask.
     /// Additionally performs "wrapped `XOR`" with result and salt, effectively
     /// encrypting the salt.
     fn apply_with_salt(&mut self, mut data: impl AsMut<[u8]>, salt: &mut [u8; 64], prog: Progress) {
-        for byte in data.as_mut() {
-            if self.mask_ptr >= 64 {
-                self.mask = self.balloon.step(prog.clone());
-                self.mask_ptr = 0;
-            }
-            *byte ^= self.mask[self.mask_ptr];
-            salt[self.salt_ptr % 64] ^= *byte;
-            self.mask_ptr += 1;
-            self.salt_ptr += 1;
+        let mut data = data.as_mut();
+        while !data.is_empty() {
+            let n = self.apply_block(data, &prog);
+            let (block, rest) = data.split_at_mut(n);
+            xor_into_salt(salt, self.salt_ptr as u64, block);
+            self.salt_ptr += n;
+            data = rest;
         }
     }
 
     /// Applies stream cipher to `data`, dynamically updating internal mask.
     /// Additionally reads in output to a provided hasher.
     fn apply_with_hash(&mut self, mut data: impl AsMut<[u8]>, hash: &mut Sha3_512, prog: Progress) {
-        for byte in data.as_mut() {
-            if self.mask_ptr >= 64 {
-                self.mask = self.balloon.step(prog.clone());
-                self.mask_ptr = 0;
-            }
-            *byte ^= self.mask[self.mask_ptr];
-            hash.update([*byte]); // TODO: Hash more than a byte at a time
-            self.mask_ptr += 1;
+        let mut data = data.as_mut();
+        while !data.is_empty() {
+            let n = self.apply_block(data, &prog);
+            let (block, rest) = data.split_at_mut(n);
+            hash.update(&*block);
+            data = rest;
         }
     }
 
@@ -112,7 +507,7 @@ impl Stream {
         prog: Progress,
     ) -> io::Result<()> {
         const BUFFER_SIZE: usize = 8 * 1024; // Same as BufReader
-        let mut buffer = [0_u8; BUFFER_SIZE];
+        let mut buffer = Zeroizing::new([0_u8; BUFFER_SIZE]);
         loop {
             let n = src.read(&mut buffer[..])?;
             if n == 0 {
@@ -132,7 +527,7 @@ impl Stream {
         prog: Progress,
     ) -> io::Result<()> {
         const BUFFER_SIZE: usize = 8 * 1024; // Same as BufReader
-        let mut buffer = [0_u8; BUFFER_SIZE];
+        let mut buffer = Zeroizing::new([0_u8; BUFFER_SIZE]);
         loop {
             let n = src.read(&mut buffer[..])?;
             if n == 0 {
@@ -145,8 +540,48 @@ impl Stream {
     }
 }
 
+/// Returns the first `len` bytes of the keystream for `settings`.
+pub(crate) fn keystream(
+    passwd: impl AsRef<[u8]>,
+    salt: Vec<u8>,
+    settings: CryptSettings,
+    len: usize,
+    prog: Progress,
+) -> Result<Vec<u8>> {
+    fn with<D: BalloonHash>(
+        passwd: impl AsRef<[u8]>,
+        salt: Vec<u8>,
+        settings: CryptSettings,
+        len: usize,
+        prog: Progress,
+    ) -> Result<Vec<u8>> {
+        let mut stream = Stream::<D>::new(passwd, salt, settings, prog.clone())?;
+        Ok(stream.keystream(len, prog))
+    }
+    match settings.hash {
+        HashFunction::Sha3_512 => with::<Sha3_512>(passwd, salt, settings, len, prog),
+        HashFunction::Blake2b512 => with::<Blake2b512>(passwd, salt, settings, len, prog),
+        HashFunction::Sha512 => with::<Sha512>(passwd, salt, settings, len, prog),
+    }
+}
+
+/// Number of lanes used before it was recorded.
+pub(crate) fn single_lane() -> u64 {
+    1
+}
+
+/// Converts a parameter of `CryptSettings` to `usize`, which may be smaller on this platform.
+pub(crate) fn to_usize(value: u64, parameter: &str) -> Result<usize> {
+    match usize::try_from(value) {
+        Ok(value) => Ok(value),
+        Err(_) => Err(Error::InvalidSettings(format!(
+            "{parameter} of {value} is too large for this platform"
+        ))),
+    }
+}
+
 /// Generate salt using entropy from OS.
-fn gen_salt() -> [u8; 64] {
+pub(crate) fn gen_salt() -> [u8; 64] {
     use rand::prelude::*;
     use rand_chacha::ChaCha20Rng;
 
@@ -157,68 +592,591 @@ fn gen_salt() -> [u8; 64] {
     res
 }
 
-fn derive_password(key: impl AsRef<[u8]>, salt: impl AsRef<[u8]>) -> io::Result<[u8; 64]> {
-    #[inline]
-    fn inner(key: impl AsRef<[u8]>, salt: impl AsRef<[u8]>) -> argon2::Result<[u8; 64]> {
-        use argon2::{Algorithm, Argon2, Params, Version};
-
-        let mut output = [0u8; 64];
-        let params = Params::new(
-            Params::DEFAULT_M_COST,
-            Params::DEFAULT_T_COST * 10,
-            Params::DEFAULT_P_COST,
-            None,
-        )?;
-        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
-        argon2.hash_password_into(key.as_ref(), salt.as_ref(), &mut output)?;
-        Ok(output)
+/// Derives a key from the password as configured by `settings.kdf`.
+/// With `settings.wrapped_key`, `key` is a random data key instead,
+/// which only needs to be bound to `salt`.
+fn derive_password(
+    key: impl Key,
+    salt: impl AsRef<[u8]>,
+    settings: &CryptSettings,
+    prog: Progress,
+) -> Result<Zeroizing<[u8; 64]>> {
+    if settings.wrapped_key {
+        let key = Kmac256::mac(key.secret(), salt.as_ref(), b"zeppelin data key");
+        return Ok(Zeroizing::new(key));
     }
+    run_kdf(key, salt, settings, prog)
+}
 
-    match inner(key, salt) {
-        Ok(inner) => Ok(inner),
-        Err(_) => Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Unable to derive password",
-        )),
+/// Runs the key derivation function of `settings` on the password.
+/// Returns `Error::MissingPepper` if a pepper is required but not provided.
+fn run_kdf(
+    key: impl Key,
+    salt: impl AsRef<[u8]>,
+    settings: &CryptSettings,
+    prog: Progress,
+) -> Result<Zeroizing<[u8; 64]>> {
+    let pepper = match (settings.peppered, key.pepper()) {
+        (true, Some(pepper)) => Some(pepper),
+        (true, None) => return Err(Error::MissingPepper),
+        (false, None) => None,
+        (false, Some(_)) => {
+            return Err(Error::KeyDerivation(
+                "Pepper provided but `peppered` is not set".to_string(),
+            ))
+        }
+    };
+
+    settings
+        .kdf
+        .derive(key.secret(), salt.as_ref(), pepper, prog)
+}
+
+/// Length of a data key wrapped by `wrap_key`:
+/// salt of the key derivation, encrypted data key and tag.
+pub(crate) const WRAPPED_KEY_LEN: usize = 3 * 64;
+
+/// Encrypts `data_key` under a key derived from the password `key` and
+/// `kdf_salt` as configured by `settings.kdf`, see `CryptSettings::wrapped_key`.
+pub(crate) fn wrap_key(
+    key: impl Key,
+    data_key: &[u8; 64],
+    kdf_salt: [u8; 64],
+    settings: &CryptSettings,
+    prog: Progress,
+) -> Result<[u8; WRAPPED_KEY_LEN]> {
+    let master = run_kdf(key, kdf_salt, settings, prog)?;
+    let mut res = [0_u8; WRAPPED_KEY_LEN];
+    res[..64].copy_from_slice(&kdf_salt);
+    res[64..128].copy_from_slice(&Kmac256::mac(&master[..], b"", b"zeppelin key wrapping"));
+    xor_in_place(&mut res[64..128], data_key);
+    let tag = Kmac256::mac(&master[..], &res[..128], b"zeppelin wrapped key");
+    res[128..].copy_from_slice(&tag);
+    Ok(res)
+}
+
+/// Inverse of `wrap_key`. Returns `Error::WrongPassword` if the tag does not match.
+pub(crate) fn unwrap_key(
+    key: impl Key,
+    wrapped: &[u8; WRAPPED_KEY_LEN],
+    settings: &CryptSettings,
+    prog: Progress,
+) -> Result<Zeroizing<[u8; 64]>> {
+    let master = run_kdf(key, &wrapped[..64], settings, prog)?;
+    let tag = Kmac256::mac(&master[..], &wrapped[..128], b"zeppelin wrapped key");
+    if !tags_equal(&wrapped[128..], &tag) {
+        return Err(Error::WrongPassword);
+    }
+    let mut data_key = Zeroizing::new(Kmac256::mac(&master[..], b"", b"zeppelin key wrapping"));
+    xor_in_place(&mut data_key[..], &wrapped[64..128]);
+    Ok(data_key)
+}
+
+/// Keys derived from the password for `Format::EncryptThenMac`.
+/// They are wiped when dropped.
+struct SubKeys {
+    encryption: [u8; 64],
+    authentication: [u8; 64],
+    check: [u8; 64],
+}
+
+impl Drop for SubKeys {
+    fn drop(&mut self) {
+        self.encryption.zeroize();
+        self.authentication.zeroize();
+        self.check.zeroize();
+    }
+}
+
+impl SubKeys {
+    fn derive(
+        key: impl Key,
+        kdf_salt: &[u8],
+        settings: &CryptSettings,
+        prog: Progress,
+    ) -> Result<Self> {
+        let master = derive_password(key, kdf_salt, settings, prog)?;
+        Ok(Self {
+            encryption: Kmac256::mac(&master[..], b"", b"zeppelin encryption"),
+            authentication: Kmac256::mac(&master[..], b"", b"zeppelin authentication"),
+            check: Kmac256::mac(&master[..], b"", b"zeppelin key check"),
+        })
+    }
+
+    /// Tag of `header`. Formats that store the salt of the `Balloon` as is
+    /// authenticate it here as well, so that tampering with it is detected
+    /// before the `Balloon` is created.
+    fn header_tag(&self, header: &[u8], salt: &[u8; 64], format: Format) -> [u8; 64] {
+        let mut mac = Kmac256::new(&self.authentication, b"zeppelin header");
+        mac.update(header);
+        if matches!(format, Format::SinglePass | Format::Chunked) {
+            mac.update(salt);
+        }
+        mac.finalize()
+    }
+
+    /// Derives the keys from the password and the salt at the start of `prefix`,
+    /// then checks the key check and the header tag following it.
+    /// `salt` is the salt of the `Balloon` as stored.
+    fn from_prefix(
+        key: impl Key,
+        prefix: &[u8; ETM_PREFIX_LEN],
+        header: &[u8],
+        salt: &[u8; 64],
+        settings: &CryptSettings,
+        prog: Progress,
+    ) -> Result<Self> {
+        let keys = Self::derive(key, &prefix[..64], settings, prog)?;
+        if !tags_equal(&prefix[64..128], &keys.check) {
+            return Err(Error::WrongPassword);
+        }
+        if !tags_equal(
+            &prefix[128..],
+            &keys.header_tag(header, salt, settings.format),
+        ) {
+            return Err(Error::HeaderTampered);
+        }
+        Ok(keys)
+    }
+
+    /// Creates the *MAC* of header and ciphertext. Each format uses its own
+    /// customization, so that a stream can not be passed off as the other format.
+    fn data_mac(&self, header: &[u8], format: Format) -> Kmac256 {
+        let customization: &[u8] = match format {
+            Format::SinglePass => b"zeppelin single-pass data",
+            Format::Chunked => b"zeppelin chunk",
+            _ => b"zeppelin data",
+        };
+        let mut mac = Kmac256::new(&self.authentication, customization);
+        mac.update((header.len() as u64).to_le_bytes());
+        mac.update(header);
+        mac
+    }
+}
+
+/// Finishes the *MAC* of a stream. For `Format::EncryptThenMac` it also covers the
+/// salt of the `Balloon`, which is absorbed last, since decrypting only recovers
+/// it from the whole stream. Otherwise a tampered salt would go unnoticed.
+fn data_tag(mut mac: Kmac256, salt: &[u8; 64], format: Format) -> [u8; 64] {
+    if format == Format::EncryptThenMac {
+        mac.update(salt);
+    }
+    mac.finalize()
+}
+
+/// Writer passing ciphertext on to `dest` while calculating its *MAC*.
+struct MacWriter<'a, W> {
+    dest: &'a mut W,
+    mac: &'a mut Kmac256,
+}
+
+impl<W: Write> Write for MacWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.dest.write(buf)?;
+        self.mac.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.dest.flush()
+    }
+}
+
+/// Reader passing ciphertext on from `source` while calculating its *MAC*.
+struct MacReader<'a, R> {
+    source: &'a mut R,
+    mac: &'a mut Kmac256,
+}
+
+impl<R: Read> Read for MacReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.source.read(buf)?;
+        self.mac.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Reader over everything but the last 64 bytes of `source`, which are the
+/// tag. Since the length of `source` is unknown, the last 64 bytes read are
+/// always held back.
+struct TagReader<R> {
+    source: R,
+    held: Vec<u8>,
+}
+
+impl<R: Read> TagReader<R> {
+    fn new(source: R) -> Self {
+        Self {
+            source,
+            held: Vec::new(),
+        }
+    }
+
+    /// Returns the tag once everything else has been read,
+    /// `None` if `source` is shorter than a tag.
+    fn tag(self) -> Option<[u8; 64]> {
+        self.held.try_into().ok()
     }
 }
 
+impl<R: Read> Read for TagReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        const BUFFER_SIZE: usize = 8 * 1024; // Same as BufReader
+        while self.held.len() <= 64 {
+            let start = self.held.len();
+            self.held.resize(start + BUFFER_SIZE, 0);
+            let n = match self.source.read(&mut self.held[start..]) {
+                Ok(n) => n,
+                Err(e) => {
+                    self.held.truncate(start);
+                    return Err(e);
+                }
+            };
+            self.held.truncate(start + n);
+            if n == 0 {
+                break;
+            }
+        }
+        let n = self.held.len().saturating_sub(64).min(buf.len());
+        buf[..n].copy_from_slice(&self.held[..n]);
+        self.held.drain(..n);
+        Ok(n)
+    }
+}
+
+/// Length of the unencrypted prefix of `Format::EncryptThenMac`:
+/// salt of the key derivation, key check and header tag.
+pub(crate) const ETM_PREFIX_LEN: usize = 3 * 64;
+
+/// Compares two tags in constant time.
+fn tags_equal(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+/// XORs `src` into `dest`, which have the same length.
+#[inline]
+fn xor_in_place(dest: &mut [u8], src: &[u8]) {
+    for (d, s) in dest.iter_mut().zip(src) {
+        *d ^= s;
+    }
+}
+
+/// Performs "wrapped `XOR`" of `data`, which starts at `offset` within the stream, into `salt`.
+fn xor_into_salt(salt: &mut [u8; 64], offset: u64, mut data: &[u8]) {
+    let mut offset = (offset % 64) as usize;
+    while !data.is_empty() {
+        let n = data.len().min(64 - offset);
+        xor_in_place(&mut salt[offset..offset + n], &data[..n]);
+        data = &data[n..];
+        offset = 0;
+    }
+}
+
+/// Value used to check the derived key before decrypting.
+fn key_check(key: &[u8; 64]) -> [u8; 64] {
+    let mut hash = Sha3_512::new();
+    hash.update(b"zeppelin key check");
+    hash.update(key);
+    hash.finalize().into()
+}
+
+/// Tag authenticating `header` under the derived key.
+fn header_tag(key: &[u8; 64], header: &[u8]) -> [u8; 64] {
+    let mut hash = Sha3_512::new();
+    hash.update(b"zeppelin header");
+    hash.update(key);
+    hash.update(header);
+    hash.finalize().into()
+}
+
+/// Creates the hasher used for the *MAC* of the plaintext.
+fn mac_hasher(key: &[u8; 64], header: &[u8], format: Format) -> Sha3_512 {
+    let mut mac_hash = Sha3_512::new();
+    mac_hash.update(key);
+    if format != Format::Legacy {
+        mac_hash.update((header.len() as u64).to_le_bytes());
+        mac_hash.update(header);
+    }
+    mac_hash
+}
+
 /// Encrypts in a stream like fashion reading from `source` and writing to `dest`.
 /// Returns `salt` needed for decryption. Resulting message contains *MAC*.
+/// Unless `settings.format` is `Format::Legacy` the `header` is authenticated
+/// as well, it is not part of the output and needs to be stored separately.
 pub fn encrypt<R: Read + Seek, W: Write>(
     source: &mut R,
     dest: &mut W,
-    key: impl AsRef<[u8]>,
+    key: impl Key,
+    header: &[u8],
+    settings: CryptSettings,
+    prog: Progress,
+) -> Result<[u8; 64]> {
+    encrypt_with_salts(
+        source,
+        dest,
+        key,
+        header,
+        settings,
+        &mut gen_salt,
+        false,
+        prog,
+    )
+}
+
+/// Like `encrypt` but computes the keystream in a separate thread while reading,
+/// hashing and writing, see `pipeline`. The output is the same.
+pub fn encrypt_pipelined<R: Read + Seek, W: Write>(
+    source: &mut R,
+    dest: &mut W,
+    key: impl Key,
+    header: &[u8],
+    settings: CryptSettings,
+    prog: Progress,
+) -> Result<[u8; 64]> {
+    encrypt_with_salts(
+        source,
+        dest,
+        key,
+        header,
+        settings,
+        &mut gen_salt,
+        true,
+        prog,
+    )
+}
+
+/// `encrypt` taking its salts from `salts` instead of the OS.
+/// Only used to create reproducible test vectors.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn encrypt_with_salts<R: Read + Seek, W: Write>(
+    source: &mut R,
+    dest: &mut W,
+    key: impl Key,
+    header: &[u8],
+    settings: CryptSettings,
+    salts: &mut dyn FnMut() -> [u8; 64],
+    pipelined: bool,
+    prog: Progress,
+) -> Result<[u8; 64]> {
+    if check_single_pass(&settings).is_ok() {
+        return encrypt_single_pass_with_salts(
+            source, dest, key, header, settings, salts, pipelined, prog,
+        );
+    }
+    match settings.hash {
+        HashFunction::Sha3_512 => encrypt_using::<Sha3_512, _, _>(
+            source, dest, key, header, settings, salts, pipelined, prog,
+        ),
+        HashFunction::Blake2b512 => encrypt_using::<Blake2b512, _, _>(
+            source, dest, key, header, settings, salts, pipelined, prog,
+        ),
+        HashFunction::Sha512 => encrypt_using::<Sha512, _, _>(
+            source, dest, key, header, settings, salts, pipelined, prog,
+        ),
+    }
+}
+
+/// Like `encrypt` but reads `source` only once and never seeks, so that it can
+/// be a pipe, stdin or a socket. The number of bytes read is reported by
+/// `Progress::get_bytes`.
+/// Only `Format::EncryptThenMac`, `Format::SinglePass` and `Format::Chunked`
+/// support this, for other formats the *MAC* has to be calculated before
+/// encrypting and `Error::InvalidSettings` is returned.
+pub fn encrypt_single_pass<R: Read, W: Write>(
+    source: &mut R,
+    dest: &mut W,
+    key: impl Key,
+    header: &[u8],
+    settings: CryptSettings,
+    prog: Progress,
+) -> Result<[u8; 64]> {
+    encrypt_single_pass_with_salts(
+        source,
+        dest,
+        key,
+        header,
+        settings,
+        &mut gen_salt,
+        false,
+        prog,
+    )
+}
+
+/// `encrypt_single_pass` taking its salts from `salts` instead of the OS.
+#[allow(clippy::too_many_arguments)]
+fn encrypt_single_pass_with_salts<R: Read, W: Write>(
+    source: &mut R,
+    dest: &mut W,
+    key: impl Key,
+    header: &[u8],
+    settings: CryptSettings,
+    salts: &mut dyn FnMut() -> [u8; 64],
+    pipelined: bool,
+    prog: Progress,
+) -> Result<[u8; 64]> {
+    check_single_pass(&settings)?;
+    let mut source = ProgressReader {
+        inner: source,
+        prog: prog.clone(),
+    };
+    let source = &mut source;
+    match settings.hash {
+        HashFunction::Sha3_512 => encrypt_single_pass_using::<Sha3_512, _, _>(
+            source, dest, key, header, settings, salts, pipelined, prog,
+        ),
+        HashFunction::Blake2b512 => encrypt_single_pass_using::<Blake2b512, _, _>(
+            source, dest, key, header, settings, salts, pipelined, prog,
+        ),
+        HashFunction::Sha512 => encrypt_single_pass_using::<Sha512, _, _>(
+            source, dest, key, header, settings, salts, pipelined, prog,
+        ),
+    }
+}
+
+/// `encrypt_single_pass` with a `Balloon` built on `D`.
+#[allow(clippy::too_many_arguments)]
+fn encrypt_single_pass_using<D: BalloonHash, R: Read, W: Write>(
+    source: &mut R,
+    dest: &mut W,
+    key: impl Key,
+    header: &[u8],
+    settings: CryptSettings,
+    salts: &mut dyn FnMut() -> [u8; 64],
+    pipelined: bool,
+    prog: Progress,
+) -> Result<[u8; 64]> {
+    match settings.format {
+        Format::Chunked => {
+            chunked::encrypt::<D, _, _>(source, dest, key, header, settings, salts, prog)
+        }
+        _ => {
+            encrypt_then_mac::<D, _, _>(source, dest, key, header, settings, salts, pipelined, prog)
+        }
+    }
+}
+
+/// Returns `Error::InvalidSettings` unless `settings` allow single-pass encryption.
+pub(crate) fn check_single_pass(settings: &CryptSettings) -> Result<()> {
+    match settings.format {
+        Format::EncryptThenMac | Format::SinglePass | Format::Chunked => Ok(()),
+        Format::Legacy | Format::AuthenticatedHeader => Err(Error::InvalidSettings(
+            "single-pass encryption requires `Format::EncryptThenMac` or newer".to_string(),
+        )),
+    }
+}
+
+/// `encrypt` for formats before `Format::EncryptThenMac` with a `Balloon` built on `D`.
+#[allow(clippy::too_many_arguments)]
+fn encrypt_using<D: BalloonHash, R: Read + Seek, W: Write>(
+    source: &mut R,
+    dest: &mut W,
+    key: impl Key,
+    header: &[u8],
     settings: CryptSettings,
+    salts: &mut dyn FnMut() -> [u8; 64],
+    pipelined: bool,
     prog: Progress,
-) -> io::Result<[u8; 64]> {
+) -> Result<[u8; 64]> {
     // Derive key
     prog.set_state("Deriving Password".to_string());
-    let mut salt = gen_salt();
-    let key = derive_password(key, salt)?;
+    let mut salt = salts();
+    let key = derive_password(key, salt, &settings, prog.clone())?;
 
     // Calculate MAC
     prog.set_state("Calculating MAC".to_string());
-    let mut mac_hash = Sha3_512::new();
-    mac_hash.update(key);
+    let mut mac_hash = mac_hasher(&key, header, settings.format);
     io::copy(source, &mut mac_hash)?;
     source.rewind()?;
-    let mac: [u8; 64] = mac_hash.finalize().into();
-    let mut mac = io::Cursor::new(mac);
+    let mac: Zeroizing<[u8; 64]> = Zeroizing::new(mac_hash.finalize().into());
+    let mut mac = io::Cursor::new(&mac[..]);
 
     // Initialize Stream
-    let mut stream = Stream::new(key, salt.to_vec(), settings, prog.clone());
+    let mut stream = Stream::<D>::new(&key[..], salt.to_vec(), settings, prog.clone())?;
 
     prog.set_state("Encrypting".to_string());
 
+    // Write unencrypted prefix, it is part of the "wrapped `XOR`" nonetheless.
+    // Its length is a multiple of 64, so the stream stays aligned with the salt.
+    if settings.format != Format::Legacy {
+        let mut prefix = [0_u8; 128];
+        prefix[..64].copy_from_slice(&key_check(&key));
+        prefix[64..].copy_from_slice(&header_tag(&key, header));
+        decrypt_salt(&mut salt, &mut &prefix[..])?;
+        dest.write_all(&prefix)?;
+    }
+
     // Encrypt and Write to output
     stream.copy_and_apply_with_salt(&mut mac, dest, &mut salt, prog.clone())?;
-    stream.copy_and_apply_with_salt(source, dest, &mut salt, prog)?;
+    if pipelined {
+        stream.pipelined_copy(source, dest, &mut salt, None, prog)?;
+    } else {
+        stream.copy_and_apply_with_salt(source, dest, &mut salt, prog)?;
+    }
+
+    Ok(salt)
+}
+
+/// `encrypt` for `Format::EncryptThenMac` and `Format::SinglePass`. The password
+/// only seeds the key derivation, whose salt is stored in front of the ciphertext.
+/// The `Balloon` is seeded with the encryption key and the salt returned to the
+/// caller, which is only wrapped with the output for `Format::EncryptThenMac`.
+#[allow(clippy::too_many_arguments)]
+fn encrypt_then_mac<D: BalloonHash, R: Read, W: Write>(
+    source: &mut R,
+    dest: &mut W,
+    key: impl Key,
+    header: &[u8],
+    settings: CryptSettings,
+    salts: &mut dyn FnMut() -> [u8; 64],
+    pipelined: bool,
+    prog: Progress,
+) -> Result<[u8; 64]> {
+    prog.set_state("Deriving Password".to_string());
+    let kdf_salt = salts();
+    let keys = SubKeys::derive(key, &kdf_salt, &settings, prog.clone())?;
+
+    let mut salt = salts();
+    let mut stream = Stream::<D>::new(&keys.encryption[..], salt.to_vec(), settings, prog.clone())?;
+
+    prog.set_state("Encrypting".to_string());
+
+    // The salt is wrapped into a copy, which is discarded for `Format::SinglePass`.
+    let mut wrapped = salt;
+
+    // The prefix is a multiple of 64 long, so the stream stays aligned with the salt.
+    let mut prefix = [0_u8; ETM_PREFIX_LEN];
+    prefix[..64].copy_from_slice(&kdf_salt);
+    prefix[64..128].copy_from_slice(&keys.check);
+    prefix[128..].copy_from_slice(&keys.header_tag(header, &salt, settings.format));
+    xor_into_salt(&mut wrapped, 0, &prefix);
+    dest.write_all(&prefix)?;
+
+    let mut mac = keys.data_mac(header, settings.format);
+    let len = if pipelined {
+        let mut absorb = |data: &[u8]| mac.update(data);
+        stream.pipelined_copy(source, dest, &mut wrapped, Some(&mut absorb), prog)?
+    } else {
+        let mut writer = MacWriter {
+            dest: &mut *dest,
+            mac: &mut mac,
+        };
+        stream.copy_and_apply_with_salt(source, &mut writer, &mut wrapped, prog)?;
+        stream.salt_ptr as u64
+    };
 
+    let tag = data_tag(mac, &salt, settings.format);
+    xor_into_salt(&mut wrapped, len, &tag);
+    dest.write_all(&tag)?;
+
+    if settings.format == Format::EncryptThenMac {
+        salt = wrapped;
+    }
     Ok(salt)
 }
 
-pub fn decrypt_salt<R: Read>(salt: &mut [u8; 64], source: &mut R) -> io::Result<()> {
+/// Decrypts the salt returned by `encrypt` by XOR'ing it with the whole encrypted stream.
+pub fn decrypt_salt<R: Read>(salt: &mut [u8; 64], source: &mut R) -> Result<()> {
     let mut salt_ptr = 0;
     const BUFFER_SIZE: usize = 8 * 1024; // Same as BufReader
     let mut buffer = [0_u8; BUFFER_SIZE];
@@ -227,45 +1185,338 @@ pub fn decrypt_salt<R: Read>(salt: &mut [u8; 64], source: &mut R) -> io::Result<
         if n == 0 {
             break;
         };
-        for item in buffer.iter().take(n) {
-            salt[salt_ptr % 64] ^= item;
-            salt_ptr += 1;
-        }
+        xor_into_salt(salt, salt_ptr, &buffer[..n]);
+        salt_ptr += n as u64;
     }
     Ok(())
 }
 
 /// Decrypts in a stream like fashion reading from `source` and writing to `dest`.
-/// Inverse of `encrypt`. Salt that was encrypted by `encrypt` needs to be decrypted
-/// separately since the reader a priori doesn't implement `std::io::Cursor`.
-/// Returns true if expected MAC and MAC of output match.
-pub fn decrypt<R: Read, W: Write>(
+/// Inverse of `encrypt`, `salt` is the one returned by it. `source` must hold
+/// exactly the output of `encrypt`, since it is read multiple times.
+///
+/// For `Format::EncryptThenMac` the MAC is verified in a first pass, the data
+/// decrypted in a second. If `source` can be modified in between, e.g. a file
+/// shared with someone else, what is decrypted may not be what was verified.
+/// Copy such sources first or use `decrypt_single_pass`, which reads them once.
+///
+/// For `Format::EncryptThenMac` nothing is written to `dest` unless password,
+/// `header` and data have been authenticated. Otherwise `Error::WrongPassword`
+/// or `Error::Corrupted` is returned if expected MAC and MAC of output do not
+/// match, in which case data has been written to `dest` nonetheless.
+///
+/// Unless `settings.format` is `Format::Legacy`, a wrong password is detected
+/// before anything is written to `dest` and `Error::HeaderTampered` is
+/// returned if `header` has been tampered with.
+pub fn decrypt<R: Read + Seek, W: Write>(
+    source: &mut R,
+    dest: &mut W,
+    key: impl Key,
+    salt: &[u8; 64],
+    header: &[u8],
+    settings: CryptSettings,
+    prog: Progress,
+) -> Result<()> {
+    decrypt_with(source, dest, key, salt, header, settings, false, prog)
+}
+
+/// Like `decrypt` but computes the keystream in a separate thread, see `encrypt_pipelined`.
+pub fn decrypt_pipelined<R: Read + Seek, W: Write>(
+    source: &mut R,
+    dest: &mut W,
+    key: impl Key,
+    salt: &[u8; 64],
+    header: &[u8],
+    settings: CryptSettings,
+    prog: Progress,
+) -> Result<()> {
+    decrypt_with(source, dest, key, salt, header, settings, true, prog)
+}
+
+/// `decrypt` or `decrypt_pipelined`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn decrypt_with<R: Read + Seek, W: Write>(
     source: &mut R,
     dest: &mut W,
-    key: impl AsRef<[u8]>,
-    decrypted_salt: &[u8; 64],
+    key: impl Key,
+    salt: &[u8; 64],
+    header: &[u8],
     settings: CryptSettings,
+    pipelined: bool,
     prog: Progress,
-) -> io::Result<bool> {
+) -> Result<()> {
+    match settings.hash {
+        HashFunction::Sha3_512 => decrypt_using::<Sha3_512, _, _>(
+            source, dest, key, salt, header, settings, pipelined, prog,
+        ),
+        HashFunction::Blake2b512 => decrypt_using::<Blake2b512, _, _>(
+            source, dest, key, salt, header, settings, pipelined, prog,
+        ),
+        HashFunction::Sha512 => decrypt_using::<Sha512, _, _>(
+            source, dest, key, salt, header, settings, pipelined, prog,
+        ),
+    }
+}
+
+/// `decrypt` with a `Balloon` built on `D`.
+#[allow(clippy::too_many_arguments)]
+fn decrypt_using<D: BalloonHash, R: Read + Seek, W: Write>(
+    source: &mut R,
+    dest: &mut W,
+    key: impl Key,
+    salt: &[u8; 64],
+    header: &[u8],
+    settings: CryptSettings,
+    pipelined: bool,
+    prog: Progress,
+) -> Result<()> {
+    match settings.format {
+        Format::EncryptThenMac => {
+            return verify_then_decrypt::<D, _, _>(
+                source, dest, key, salt, header, settings, pipelined, prog,
+            )
+        }
+        Format::SinglePass | Format::Chunked => {
+            source.rewind()?;
+            return decrypt_single_pass_using::<D, _, _>(
+                source, dest, key, salt, header, settings, pipelined, prog,
+            );
+        }
+        _ => {}
+    }
+
+    prog.set_state("Decrypting salt".to_string());
+    let mut decrypted_salt = *salt;
+    source.rewind()?;
+    decrypt_salt(&mut decrypted_salt, source)?;
+    source.rewind()?;
+
     prog.set_state("Deriving Password".to_string());
-    let key = derive_password(key, decrypted_salt)?;
+    let key = derive_password(key, decrypted_salt, &settings, prog.clone())?;
+
+    if settings.format != Format::Legacy {
+        let mut prefix = [0_u8; 128];
+        source.read_exact(&mut prefix)?;
+        if !tags_equal(&prefix[..64], &key_check(&key)) {
+            return Err(Error::WrongPassword);
+        }
+        if !tags_equal(&prefix[64..], &header_tag(&key, header)) {
+            return Err(Error::HeaderTampered);
+        }
+    }
 
-    let mut expected_mac = [0_u8; 64];
+    let mut expected_mac = Zeroizing::new([0_u8; 64]);
 
-    let mut stream = Stream::new(key, decrypted_salt.to_vec(), settings, prog.clone());
+    let mut stream = Stream::<D>::new(&key[..], decrypted_salt.to_vec(), settings, prog.clone())?;
 
     prog.set_state("Decrypting".to_string());
 
-    source.read_exact(&mut expected_mac)?;
-    stream.apply_with_salt(&mut expected_mac, &mut [0_u8; 64], prog.clone());
+    source.read_exact(&mut expected_mac[..])?;
+    stream.apply_with_salt(&mut expected_mac[..], &mut [0_u8; 64], prog.clone());
 
-    let mut mac_hash = Sha3_512::new();
-    mac_hash.update(key);
-    stream.copy_and_apply_with_hash(source, dest, &mut mac_hash, prog)?;
+    let mut mac_hash = mac_hasher(&key, header, settings.format);
+    if pipelined {
+        let mut absorb = |data: &[u8]| Digest::update(&mut mac_hash, data);
+        stream.pipelined_copy(source, dest, &mut [0_u8; 64], Some(&mut absorb), prog)?;
+    } else {
+        stream.copy_and_apply_with_hash(source, dest, &mut mac_hash, prog)?;
+    }
+
+    let mac: Zeroizing<[u8; 64]> = Zeroizing::new(mac_hash.finalize().into());
+
+    if tags_equal(&expected_mac[..], &mac[..]) {
+        Ok(())
+    } else if settings.format == Format::Legacy {
+        Err(Error::WrongPassword)
+    } else {
+        Err(Error::Corrupted)
+    }
+}
+
+/// Like `decrypt` but reads `source` only once and never seeks, so that it can
+/// be a pipe, stdin or a socket.
+/// Only `Format::SinglePass` and `Format::Chunked` support this, otherwise
+/// `Error::InvalidSettings` is returned.
+/// ### Note:
+/// Password and `header` are checked before anything is written to `dest`.
+/// With `Format::SinglePass` the data is written while it is decrypted and
+/// `Error::Corrupted` is only returned once all of it has been written.
+/// With `Format::Chunked` every chunk is authenticated before it is written,
+/// so only a prefix of the data can have been written when
+/// `Error::CorruptedChunk` or `Error::Truncated` is returned.
+pub fn decrypt_single_pass<R: Read, W: Write>(
+    source: &mut R,
+    dest: &mut W,
+    key: impl Key,
+    salt: &[u8; 64],
+    header: &[u8],
+    settings: CryptSettings,
+    prog: Progress,
+) -> Result<()> {
+    if !matches!(settings.format, Format::SinglePass | Format::Chunked) {
+        return Err(Error::InvalidSettings(
+            "single-pass decryption requires `Format::SinglePass` or `Format::Chunked`".to_string(),
+        ));
+    }
+    match settings.hash {
+        HashFunction::Sha3_512 => decrypt_single_pass_using::<Sha3_512, _, _>(
+            source, dest, key, salt, header, settings, false, prog,
+        ),
+        HashFunction::Blake2b512 => decrypt_single_pass_using::<Blake2b512, _, _>(
+            source, dest, key, salt, header, settings, false, prog,
+        ),
+        HashFunction::Sha512 => decrypt_single_pass_using::<Sha512, _, _>(
+            source, dest, key, salt, header, settings, false, prog,
+        ),
+    }
+}
+
+/// `decrypt_single_pass` with a `Balloon` built on `D`.
+#[allow(clippy::too_many_arguments)]
+fn decrypt_single_pass_using<D: BalloonHash, R: Read, W: Write>(
+    source: &mut R,
+    dest: &mut W,
+    key: impl Key,
+    salt: &[u8; 64],
+    header: &[u8],
+    settings: CryptSettings,
+    pipelined: bool,
+    prog: Progress,
+) -> Result<()> {
+    match settings.format {
+        Format::Chunked => {
+            chunked::decrypt::<D, _, _>(source, dest, key, salt, header, settings, prog)
+        }
+        _ => decrypt_while_verifying::<D, _, _>(
+            source, dest, key, salt, header, settings, pipelined, prog,
+        ),
+    }
+}
+
+/// `decrypt_single_pass` for `Format::SinglePass`. The *MAC* is calculated
+/// while decrypting and checked against the tag at the end.
+#[allow(clippy::too_many_arguments)]
+fn decrypt_while_verifying<D: BalloonHash, R: Read, W: Write>(
+    source: &mut R,
+    dest: &mut W,
+    key: impl Key,
+    salt: &[u8; 64],
+    header: &[u8],
+    settings: CryptSettings,
+    pipelined: bool,
+    prog: Progress,
+) -> Result<()> {
+    prog.set_state("Deriving Password".to_string());
+    let mut prefix = [0_u8; ETM_PREFIX_LEN];
+    source.read_exact(&mut prefix).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => Error::Corrupted,
+        _ => e.into(),
+    })?;
+    let keys = SubKeys::from_prefix(key, &prefix, header, salt, &settings, prog.clone())?;
+
+    let mut stream = Stream::<D>::new(&keys.encryption[..], salt.to_vec(), settings, prog.clone())?;
+
+    prog.set_state("Decrypting".to_string());
+    let mut mac = keys.data_mac(header, settings.format);
+    let mut ciphertext = TagReader::new(source);
+    let mut reader = MacReader {
+        source: &mut ciphertext,
+        mac: &mut mac,
+    };
+    if pipelined {
+        stream.pipelined_copy(&mut reader, dest, &mut [0_u8; 64], None, prog)?;
+    } else {
+        stream.copy_and_apply_with_salt(&mut reader, dest, &mut [0_u8; 64], prog)?;
+    }
+
+    match ciphertext.tag() {
+        Some(tag) if tags_equal(&tag, &data_tag(mac, salt, settings.format)) => Ok(()),
+        _ => Err(Error::Corrupted),
+    }
+}
 
-    let mac: [u8; 64] = mac_hash.finalize().into();
+/// `decrypt` for `Format::EncryptThenMac`. Reads the ciphertext twice, first
+/// to check its *MAC* and recover the salt, then to decrypt it. The second pass
+/// trusts `source` not to have changed, see `decrypt`.
+#[allow(clippy::too_many_arguments)]
+fn verify_then_decrypt<D: BalloonHash, R: Read + Seek, W: Write>(
+    source: &mut R,
+    dest: &mut W,
+    key: impl Key,
+    salt: &[u8; 64],
+    header: &[u8],
+    settings: CryptSettings,
+    pipelined: bool,
+    prog: Progress,
+) -> Result<()> {
+    let (keys, salt, ciphertext_len) = verify_mac(source, key, salt, header, settings, &prog)?;
+
+    let mut stream = Stream::<D>::new(&keys.encryption[..], salt.to_vec(), settings, prog.clone())?;
+
+    prog.set_state("Decrypting".to_string());
+    source.seek(SeekFrom::Start(ETM_PREFIX_LEN as u64))?;
+    let mut ciphertext = (&mut *source).take(ciphertext_len);
+    if pipelined {
+        stream.pipelined_copy(&mut ciphertext, dest, &mut [0_u8; 64], None, prog)?;
+    } else {
+        stream.copy_and_apply_with_salt(&mut ciphertext, dest, &mut [0_u8; 64], prog)?;
+    }
+
+    Ok(())
+}
+
+/// Checks password, `header` and *MAC* of a stream in `Format::EncryptThenMac`
+/// or `Format::SinglePass` without creating the `Balloon`. Returns the derived
+/// keys, the decrypted salt and the length of the ciphertext.
+fn verify_mac<R: Read + Seek>(
+    source: &mut R,
+    key: impl Key,
+    salt: &[u8; 64],
+    header: &[u8],
+    settings: CryptSettings,
+    prog: &Progress,
+) -> Result<(SubKeys, [u8; 64], u64)> {
+    let len = source.seek(SeekFrom::End(0))?;
+    let Some(ciphertext_len) = len.checked_sub(ETM_PREFIX_LEN as u64 + 64) else {
+        return Err(Error::Corrupted);
+    };
+    source.rewind()?;
+
+    prog.set_state("Deriving Password".to_string());
+    let mut prefix = [0_u8; ETM_PREFIX_LEN];
+    source.read_exact(&mut prefix)?;
+    let keys = SubKeys::from_prefix(key, &prefix, header, salt, &settings, prog.clone())?;
+
+    prog.set_state("Verifying MAC".to_string());
+    let mut wrapped = *salt;
+    xor_into_salt(&mut wrapped, 0, &prefix);
+    let mut mac = keys.data_mac(header, settings.format);
+
+    let mut ciphertext = (&mut *source).take(ciphertext_len);
+    let mut offset = ETM_PREFIX_LEN as u64;
+    const BUFFER_SIZE: usize = 8 * 1024; // Same as BufReader
+    let mut buffer = [0_u8; BUFFER_SIZE];
+    loop {
+        let n = ciphertext.read(&mut buffer[..])?;
+        if n == 0 {
+            break;
+        };
+        mac.update(&buffer[..n]);
+        xor_into_salt(&mut wrapped, offset, &buffer[..n]);
+        offset += n as u64;
+    }
 
-    Ok(expected_mac == mac)
+    let mut tag = [0_u8; 64];
+    source.read_exact(&mut tag)?;
+    xor_into_salt(&mut wrapped, offset, &tag);
+    let salt = match settings.format {
+        Format::SinglePass => *salt,
+        _ => wrapped,
+    };
+    if !tags_equal(&tag, &data_tag(mac, &salt, settings.format)) {
+        return Err(Error::Corrupted);
+    }
+    Ok((keys, salt, ciphertext_len))
 }
 
 #[cfg(test)]
@@ -275,7 +1526,9 @@ mod tests {
 
     use super::*;
     use crate::cipher::CryptSettings;
+    use crate::kdf::Pepper;
     use crate::progress::Progress;
+    use crate::secret::Peppered;
 
     #[test]
     fn apply_and_copy() {
@@ -284,18 +1537,20 @@ mod tests {
         let mut data2 = Vec::<u8>::new();
 
         let salt: Vec<u8> = vec![1, 2, 3];
-        let mut s1 = Stream::new(
+        let mut s1 = Stream::<Sha3_512>::new(
             b"123",
             salt.clone(),
             CryptSettings::default_for_testing(),
             Progress::new(),
-        );
-        let mut s2 = Stream::new(
+        )
+        .unwrap();
+        let mut s2 = Stream::<Sha3_512>::new(
             b"123",
             salt.clone(),
             CryptSettings::default_for_testing(),
             Progress::new(),
-        );
+        )
+        .unwrap();
 
         let mut salt1 = [0u8; 64];
         let mut salt2 = [0u8; 64];
@@ -316,8 +1571,9 @@ mod tests {
         let salt = vec![1, 2, 3];
         let settings = CryptSettings::default_for_testing();
         let prog = Progress::new();
-        let mut s1 = Stream::new(passwd, salt.clone(), settings, Progress::new());
-        let mut s2 = Stream::new(passwd, salt, settings, prog.clone());
+        let mut s1 =
+            Stream::<Sha3_512>::new(passwd, salt.clone(), settings, Progress::new()).unwrap();
+        let mut s2 = Stream::<Sha3_512>::new(passwd, salt, settings, prog.clone()).unwrap();
 
         let mut data: Vec<u8> = (0..10_u64.pow(6)).map(|b| b as u8).collect();
         let mut data2 = data.clone();
@@ -328,6 +1584,387 @@ mod tests {
         assert_eq!(data, data2)
     }
 
+    /// `encrypt_single_pass` has to work on a source that can not seek and
+    /// still count every byte, while formats that need to seek are rejected.
+    #[test]
+    fn single_pass_from_pipe() {
+        let settings = CryptSettings::default_for_testing();
+        let data: Vec<u8> = (0..20_000_u32).map(|b| b as u8).collect();
+
+        // `&[u8]` implements `Read` but not `Seek`
+        let prog = Progress::new();
+        let mut ciphertext = Cursor::new(Vec::new());
+        let salt = encrypt_single_pass(
+            &mut &data[..],
+            &mut ciphertext,
+            "password",
+            b"header",
+            settings,
+            prog.clone(),
+        )
+        .unwrap();
+        assert_eq!(prog.get_bytes(), data.len() as u64);
+
+        let mut res = Vec::new();
+        decrypt(
+            &mut ciphertext,
+            &mut res,
+            "password",
+            &salt,
+            b"header",
+            settings,
+            Progress::new(),
+        )
+        .unwrap();
+        assert_eq!(res, data);
+
+        let legacy = CryptSettings {
+            format: Format::AuthenticatedHeader,
+            ..settings
+        };
+        assert!(matches!(
+            encrypt_single_pass(
+                &mut &data[..],
+                &mut Vec::new(),
+                "password",
+                b"header",
+                legacy,
+                Progress::new(),
+            ),
+            Err(Error::InvalidSettings(_))
+        ));
+    }
+
+    /// Reader returning at most one byte per call.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(1);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn tag_reader_holds_back_tag() {
+        for len in [64, 65, 100, 20_000] {
+            let data: Vec<u8> = (0..len as u32).map(|b| (b * 3) as u8).collect();
+            for trickle in [false, true] {
+                let mut reader = if trickle {
+                    TagReader::new(Box::new(Trickle(&data)) as Box<dyn Read>)
+                } else {
+                    TagReader::new(Box::new(&data[..]) as Box<dyn Read>)
+                };
+                let mut res = Vec::new();
+                reader.read_to_end(&mut res).unwrap();
+                assert_eq!(res, data[..len - 64]);
+                assert_eq!(reader.tag().unwrap(), data[len - 64..]);
+            }
+        }
+        let mut reader = TagReader::new(&[0_u8; 63][..]);
+        assert_eq!(reader.read(&mut [0; 10]).unwrap(), 0);
+        assert!(reader.tag().is_none());
+    }
+
+    #[test]
+    fn single_pass_decryption() {
+        let settings = CryptSettings {
+            format: Format::SinglePass,
+            ..CryptSettings::default_for_testing()
+        };
+        for len in [0, 1, 64, 20_000] {
+            let data: Vec<u8> = (0..len as u32).map(|b| (b * 7) as u8).collect();
+            let (ciphertext, salt) = encrypt_for_test(&data, b"header", settings);
+
+            let mut res = Vec::new();
+            decrypt_single_pass(
+                &mut Trickle(&ciphertext),
+                &mut res,
+                "password",
+                &salt,
+                b"header",
+                settings,
+                Progress::new(),
+            )
+            .unwrap();
+            assert_eq!(res, data);
+
+            for pipelined in [false, true] {
+                let mut res = Vec::new();
+                decrypt_with(
+                    &mut Cursor::new(&ciphertext),
+                    &mut res,
+                    "password",
+                    &salt,
+                    b"header",
+                    settings,
+                    pipelined,
+                    Progress::new(),
+                )
+                .unwrap();
+                assert_eq!(res, data);
+            }
+        }
+
+        let (ciphertext, salt) = encrypt_for_test(b"Secret message", b"header", settings);
+        let decrypt_from = |source: &[u8], password: &str, header: &[u8]| {
+            decrypt_single_pass(
+                &mut &source[..],
+                &mut Vec::new(),
+                password,
+                &salt,
+                header,
+                settings,
+                Progress::new(),
+            )
+        };
+        let mut corrupted = ciphertext.clone();
+        corrupted[ETM_PREFIX_LEN] ^= 1;
+        assert!(matches!(
+            decrypt_from(&corrupted, "password", b"header"),
+            Err(Error::Corrupted)
+        ));
+        assert!(matches!(
+            decrypt_from(&ciphertext[..ciphertext.len() - 1], "password", b"header"),
+            Err(Error::Corrupted)
+        ));
+        assert!(matches!(
+            decrypt_from(&ciphertext[..100], "password", b"header"),
+            Err(Error::Corrupted)
+        ));
+        assert!(matches!(
+            decrypt_from(&ciphertext, "wrong", b"header"),
+            Err(Error::WrongPassword)
+        ));
+        assert!(matches!(
+            decrypt_from(&ciphertext, "password", b"tampered"),
+            Err(Error::HeaderTampered)
+        ));
+
+        // The salt is stored as is, so it is authenticated along with the header
+        // before anything is decrypted
+        let mut tampered = salt;
+        tampered[10] ^= 1;
+        let mut res = Vec::new();
+        let status = decrypt_single_pass(
+            &mut &ciphertext[..],
+            &mut res,
+            "password",
+            &tampered,
+            b"header",
+            settings,
+            Progress::new(),
+        );
+        assert!(matches!(status, Err(Error::HeaderTampered)));
+        assert!(res.is_empty());
+
+        // A stream in `Format::EncryptThenMac` can not be passed off as `Format::SinglePass`
+        let etm = CryptSettings {
+            format: Format::EncryptThenMac,
+            ..settings
+        };
+        let (ciphertext, _) = encrypt_for_test(b"Secret message", b"header", etm);
+        assert!(matches!(
+            decrypt_from(&ciphertext, "password", b"header"),
+            Err(Error::HeaderTampered)
+        ));
+        assert!(matches!(
+            decrypt_single_pass(
+                &mut &ciphertext[..],
+                &mut Vec::new(),
+                "password",
+                &salt,
+                b"header",
+                etm,
+                Progress::new(),
+            ),
+            Err(Error::InvalidSettings(_))
+        ));
+    }
+
+    #[test]
+    fn counter_mode_seeks() {
+        let settings = CryptSettings {
+            mode: KeystreamMode::Counter,
+            ..CryptSettings::default_for_testing()
+        };
+        let new_stream =
+            || Stream::<Sha3_512>::new("password", vec![1, 2, 3], settings, Progress::new());
+        let keystream = new_stream().unwrap().keystream(1000, Progress::new());
+        assert_ne!(
+            keystream,
+            Stream::<Sha3_512>::new(
+                "password",
+                vec![1, 2, 3],
+                CryptSettings::default_for_testing(),
+                Progress::new()
+            )
+            .unwrap()
+            .keystream(1000, Progress::new())
+        );
+
+        let mut stream = new_stream().unwrap();
+        for offset in [500, 0, 63, 64, 999, 130] {
+            stream.seek(offset as u64, &Progress::new()).unwrap();
+            let end = (offset + 100).min(1000);
+            assert_eq!(
+                stream.keystream(end - offset, Progress::new()),
+                keystream[offset..end]
+            );
+        }
+    }
+
+    #[test]
+    fn sequential_mode_seeks_forward() {
+        let settings = CryptSettings::default_for_testing();
+        let new_stream =
+            || Stream::<Sha3_512>::new("password", vec![1, 2, 3], settings, Progress::new());
+        let keystream = new_stream().unwrap().keystream(300, Progress::new());
+
+        let mut stream = new_stream().unwrap();
+        stream.seek(70, &Progress::new()).unwrap();
+        assert_eq!(stream.keystream(30, Progress::new()), keystream[70..100]);
+        stream.seek(100, &Progress::new()).unwrap();
+        stream.seek(250, &Progress::new()).unwrap();
+        assert_eq!(stream.keystream(50, Progress::new()), keystream[250..]);
+        assert!(stream.seek(0, &Progress::new()).is_err());
+    }
+
+    /// Applying the stream in arbitrary pieces has to match applying it byte by byte.
+    #[test]
+    fn apply_in_pieces_matches_bytewise() {
+        let settings = CryptSettings::default_for_testing();
+        let data: Vec<u8> = (0..10_000_u64).map(|b| (b * 7) as u8).collect();
+
+        let mut reference =
+            Stream::<Sha3_512>::new("password", vec![1, 2, 3], settings, Progress::new()).unwrap();
+        let keystream = reference.keystream(data.len(), Progress::new());
+        let mut expected = data.clone();
+        let mut expected_salt = [0_u8; 64];
+        for (i, byte) in expected.iter_mut().enumerate() {
+            *byte ^= keystream[i];
+            expected_salt[i % 64] ^= *byte;
+        }
+
+        for piece in [1, 3, 63, 64, 65, 1000] {
+            let mut s1 =
+                Stream::<Sha3_512>::new("password", vec![1, 2, 3], settings, Progress::new())
+                    .unwrap();
+            let mut s2 =
+                Stream::<Sha3_512>::new("password", vec![1, 2, 3], settings, Progress::new())
+                    .unwrap();
+            let mut salt = [0_u8; 64];
+            let mut hash = Sha3_512::new();
+            let mut res1 = data.clone();
+            let mut res2 = data.clone();
+            for (c1, c2) in res1.chunks_mut(piece).zip(res2.chunks_mut(piece)) {
+                s1.apply_with_salt(c1, &mut salt, Progress::new());
+                s2.apply_with_hash(c2, &mut hash, Progress::new());
+            }
+            assert_eq!(res1, expected);
+            assert_eq!(res2, expected);
+            assert_eq!(salt, expected_salt);
+            assert_eq!(hash.finalize()[..], Sha3_512::digest(&expected)[..]);
+        }
+    }
+
+    #[test]
+    fn encrypt_with_lanes() {
+        let settings = CryptSettings {
+            p_cost: 4,
+            ..CryptSettings::default_for_testing()
+        };
+        let mut ciphertext = Cursor::new(Vec::new());
+        let salt = encrypt(
+            &mut Cursor::new(b"data"),
+            &mut ciphertext,
+            "password",
+            b"",
+            settings,
+            Progress::new(),
+        )
+        .unwrap();
+
+        let mut res = Vec::new();
+        decrypt(
+            &mut ciphertext,
+            &mut res,
+            "password",
+            &salt,
+            b"",
+            settings,
+            Progress::new(),
+        )
+        .unwrap();
+        assert_eq!(res, b"data");
+
+        // The lanes are part of the keystream
+        let mut res = Vec::new();
+        decrypt(
+            &mut ciphertext,
+            &mut res,
+            "password",
+            &salt,
+            b"",
+            CryptSettings::default_for_testing(),
+            Progress::new(),
+        )
+        .unwrap();
+        assert_ne!(res, b"data");
+    }
+
+    /// Pipelining must not change the output of any format.
+    #[test]
+    fn pipelined_encryption_is_identical() {
+        let data: Vec<u8> = (0..200_000_u32).map(|b| (b * 3) as u8).collect();
+        for format in [
+            Format::Legacy,
+            Format::AuthenticatedHeader,
+            Format::EncryptThenMac,
+        ] {
+            let settings = CryptSettings {
+                format,
+                ..CryptSettings::default_for_testing()
+            };
+            let encrypt = |pipelined: bool| {
+                let mut dest = Vec::new();
+                let mut n = 0;
+                let salt = encrypt_with_salts(
+                    &mut Cursor::new(&data),
+                    &mut dest,
+                    "password",
+                    b"header",
+                    settings,
+                    &mut || {
+                        n += 1;
+                        [n; 64]
+                    },
+                    pipelined,
+                    Progress::new(),
+                )
+                .unwrap();
+                (salt, dest)
+            };
+            let (salt, ciphertext) = encrypt(false);
+            assert_eq!((salt, ciphertext.clone()), encrypt(true));
+
+            let mut res = Vec::new();
+            decrypt_pipelined(
+                &mut Cursor::new(ciphertext),
+                &mut res,
+                "password",
+                &salt,
+                b"header",
+                settings,
+                Progress::new(),
+            )
+            .unwrap();
+            assert_eq!(res, data);
+        }
+    }
+
     #[test]
     fn encrypt_and_decrypt() {
         let key = "password";
@@ -340,20 +1977,352 @@ mod tests {
         let mut dest = Cursor::new(Vec::<u8>::new());
         let mut dest2 = Cursor::new(Vec::<u8>::new());
 
-        let mut salt = encrypt(&mut source, &mut dest, key, settings, prog.clone()).unwrap();
+        let salt = encrypt(
+            &mut source,
+            &mut dest,
+            key,
+            b"header",
+            settings,
+            prog.clone(),
+        )
+        .unwrap();
 
-        prog.set_state("Decrypting salt".to_string());
+        decrypt(&mut dest, &mut dest2, key, &salt, b"header", settings, prog).unwrap();
 
-        dest.rewind().unwrap();
-        decrypt_salt(&mut salt, &mut dest).unwrap();
-        dest.rewind().unwrap();
+        assert_eq!(data, dest2.into_inner());
+    }
 
-        decrypt(&mut dest, &mut dest2, key, &salt, settings, prog).unwrap();
+    /// Encrypts `data` and returns the ciphertext together with the salt.
+    fn encrypt_for_test(
+        data: &[u8],
+        header: &[u8],
+        settings: CryptSettings,
+    ) -> (Vec<u8>, [u8; 64]) {
+        let mut dest = Vec::new();
+        let salt = encrypt(
+            &mut Cursor::new(data),
+            &mut dest,
+            "password",
+            header,
+            settings,
+            Progress::new(),
+        )
+        .unwrap();
+        (dest, salt)
+    }
 
-        assert_eq!(data, dest2.into_inner());
+    #[test]
+    fn header_tampering_detected() {
+        let settings = CryptSettings::default_for_testing();
+        let (data, salt) = encrypt_for_test(b"message", b"header", settings);
+
+        let mut dest = Vec::new();
+        let res = decrypt(
+            &mut Cursor::new(&data),
+            &mut dest,
+            "password",
+            &salt,
+            b"tampered",
+            settings,
+            Progress::new(),
+        );
+
+        assert!(matches!(res, Err(Error::HeaderTampered)));
+        assert!(dest.is_empty());
+    }
+
+    #[test]
+    fn wrong_password_detected_early() {
+        let settings = CryptSettings::default_for_testing();
+        let (data, salt) = encrypt_for_test(b"message", b"header", settings);
+
+        let mut dest = Vec::new();
+        let res = decrypt(
+            &mut Cursor::new(&data),
+            &mut dest,
+            "wrong",
+            &salt,
+            b"header",
+            settings,
+            Progress::new(),
+        );
+
+        assert!(matches!(res, Err(Error::WrongPassword)));
+        assert!(dest.is_empty());
+    }
+
+    #[test]
+    fn legacy_format_ignores_header() {
+        let settings = CryptSettings {
+            format: Format::Legacy,
+            ..CryptSettings::default_for_testing()
+        };
+        let (data, salt) = encrypt_for_test(b"message", b"header", settings);
+
+        let mut dest = Vec::new();
+        decrypt(
+            &mut Cursor::new(&data),
+            &mut dest,
+            "password",
+            &salt,
+            b"other",
+            settings,
+            Progress::new(),
+        )
+        .unwrap();
+
+        assert_eq!(dest, b"message");
+    }
+
+    #[test]
+    fn corrupted_data_detected() {
+        let settings = CryptSettings::default_for_testing();
+        let (data, salt) = encrypt_for_test(b"message", b"header", settings);
+
+        // Flip a bit of the ciphertext and of the tag
+        for pos in [ETM_PREFIX_LEN, data.len() - 1] {
+            let mut data = data.clone();
+            data[pos] ^= 1;
+
+            let mut dest = Vec::new();
+            let res = decrypt(
+                &mut Cursor::new(&data),
+                &mut dest,
+                "password",
+                &salt,
+                b"header",
+                settings,
+                Progress::new(),
+            );
+
+            assert!(matches!(res, Err(Error::Corrupted)));
+            assert!(dest.is_empty());
+        }
+    }
+
+    #[test]
+    fn tampered_salt_detected() {
+        let settings = CryptSettings::default_for_testing();
+        let (data, mut salt) = encrypt_for_test(b"message", b"header", settings);
+        salt[10] ^= 1;
+
+        let mut dest = Vec::new();
+        let res = decrypt(
+            &mut Cursor::new(&data),
+            &mut dest,
+            "password",
+            &salt,
+            b"header",
+            settings,
+            Progress::new(),
+        );
+        assert!(matches!(res, Err(Error::Corrupted)));
+        assert!(dest.is_empty());
+    }
+
+    #[test]
+    fn truncated_data_detected() {
+        let settings = CryptSettings::default_for_testing();
+        let (data, salt) = encrypt_for_test(b"", b"header", settings);
+
+        let res = decrypt(
+            &mut Cursor::new(&data[..data.len() - 1]),
+            &mut Vec::new(),
+            "password",
+            &salt,
+            b"header",
+            settings,
+            Progress::new(),
+        );
+
+        assert!(matches!(res, Err(Error::Corrupted)));
+    }
+
+    /// Streams created with older formats must still decrypt.
+    #[test]
+    fn older_formats_still_decrypt() {
+        for format in [Format::Legacy, Format::AuthenticatedHeader] {
+            let settings = CryptSettings {
+                format,
+                ..CryptSettings::default_for_testing()
+            };
+            let (data, salt) = encrypt_for_test(b"message", b"header", settings);
+
+            let mut dest = Vec::new();
+            decrypt(
+                &mut Cursor::new(&data),
+                &mut dest,
+                "password",
+                &salt,
+                b"header",
+                settings,
+                Progress::new(),
+            )
+            .unwrap();
+
+            assert_eq!(dest, b"message");
+        }
+    }
+
+    #[test]
+    fn kdf_settings_are_honoured() {
+        let settings = CryptSettings::default_for_testing();
+        let (data, salt) = encrypt_for_test(b"message", b"header", settings);
+
+        let other = CryptSettings {
+            kdf: KdfSettings::Argon2id(Argon2Settings {
+                m_cost: 256,
+                t_cost: 3,
+                p_cost: 1,
+            }),
+            ..settings
+        };
+        let res = decrypt(
+            &mut Cursor::new(&data),
+            &mut Vec::new(),
+            "password",
+            &salt,
+            b"header",
+            other,
+            Progress::new(),
+        );
+
+        assert!(matches!(res, Err(Error::WrongPassword)));
+    }
+
+    #[test]
+    fn inconsistent_pepper_rejected() {
+        let settings = CryptSettings::default_for_testing();
+        let pepper = Pepper::new([1; 32]);
+        let res = encrypt(
+            &mut Cursor::new(b"message"),
+            &mut Vec::new(),
+            Peppered::new("password", &pepper),
+            b"header",
+            settings,
+            Progress::new(),
+        );
+        assert!(matches!(res, Err(Error::KeyDerivation(_))));
+    }
+
+    #[test]
+    fn wrapped_key_round_trip() {
+        let settings = CryptSettings::default_for_testing().with_wrapped_key();
+        let data_key = [3; 64];
+        let wrapped = wrap_key("password", &data_key, [5; 64], &settings, Progress::new()).unwrap();
+        assert_ne!(wrapped[64..128], data_key);
+
+        let res = unwrap_key("password", &wrapped, &settings, Progress::new()).unwrap();
+        assert_eq!(*res, data_key);
+        let res = unwrap_key("wrong", &wrapped, &settings, Progress::new());
+        assert!(matches!(res, Err(Error::WrongPassword)));
+
+        let mut tampered = wrapped;
+        tampered[100] ^= 1;
+        let res = unwrap_key("password", &tampered, &settings, Progress::new());
+        assert!(matches!(res, Err(Error::WrongPassword)));
     }
 
-    // Todo: Add a test that checks decryption with wrong key
+    #[test]
+    fn calibrate_respects_max_memory() {
+        let max_memory = 1 << 20;
+        let calibration = CryptSettings::calibrate(Duration::from_millis(100), max_memory).unwrap();
+        let settings = calibration.settings;
+
+        assert!(settings.s_cost * 64 <= max_memory);
+        assert!(settings.t_cost >= 1);
+        assert!(matches!(
+            settings.kdf,
+            KdfSettings::Argon2id(kdf) if kdf.m_cost as u64 * 1024 <= max_memory
+        ));
+        assert!(calibration.throughput > 0.0);
+    }
+
+    #[test]
+    fn calibrate_stays_within_default_policy() {
+        use crate::container::{create_container, read_container, DecryptPolicy};
+
+        // Little memory and much time would ask for many passes
+        let calibration = CryptSettings::calibrate(Duration::from_secs(2), 1 << 10).unwrap();
+        let policy = DecryptPolicy::default();
+        assert!(policy.check(&calibration.settings).is_ok());
+
+        // More memory than allowed is capped
+        let calibration = CryptSettings::calibrate(Duration::from_millis(1), 1 << 40).unwrap();
+        assert!(policy.check(&calibration.settings).is_ok());
+
+        let settings = CryptSettings::calibrate(Duration::from_millis(20), 1 << 16)
+            .unwrap()
+            .settings;
+        let mut container = Cursor::new(Vec::new());
+        create_container(
+            &mut Cursor::new(b"data".to_vec()),
+            &mut container,
+            "password",
+            settings,
+            None,
+        )
+        .unwrap();
+        container.rewind().unwrap();
+        let mut res = Vec::new();
+        read_container(&mut container, &mut res, "password", policy, None).unwrap();
+        assert_eq!(res, b"data");
+    }
+
+    #[test]
+    fn presets_by_name() {
+        for preset in Preset::ALL {
+            let settings = CryptSettings::from_preset(preset);
+            assert_eq!(settings.preset, Some(preset));
+            assert_eq!(preset.name().parse::<Preset>().unwrap(), preset);
+            assert!(crate::container::DecryptPolicy::default()
+                .check(&settings)
+                .is_ok());
+
+            let serial = serde_json::to_string(&settings).unwrap();
+            assert!(serial.contains(&format!(r#""preset":"{preset}""#)));
+            assert_eq!(
+                serde_json::from_str::<CryptSettings>(&serial).unwrap(),
+                settings
+            );
+        }
+        assert!(matches!(
+            "insane-v1".parse::<Preset>(),
+            Err(Error::UnknownPreset(_))
+        ));
+    }
+
+    #[test]
+    fn presets_are_fixed() {
+        let presets = [
+            (Preset::InteractiveV1, 1 << 16, 2, 3, 19456, 2),
+            (Preset::ModerateV1, 468750, 2, 3, 65536, 3),
+            (Preset::SensitiveV1, 1 << 22, 3, 3, 262144, 4),
+            (Preset::ArchivalV1, 1 << 24, 4, 4, 1048576, 4),
+        ];
+        for (preset, s_cost, t_cost, step_delta, m_cost, kdf_t_cost) in presets {
+            let expected = CryptSettings {
+                s_cost,
+                t_cost,
+                step_delta,
+                p_cost: 1,
+                format: Format::EncryptThenMac,
+                revision: Revision::V2,
+                indexing: Indexing::Independent,
+                hash: HashFunction::Sha3_512,
+                mode: KeystreamMode::Sequential,
+                kdf: KdfSettings::Argon2id(Argon2Settings {
+                    m_cost,
+                    t_cost: kdf_t_cost,
+                    p_cost: 1,
+                }),
+                peppered: false,
+                wrapped_key: false,
+                preset: Some(preset),
+            };
+            assert_eq!(preset.settings(), expected);
+        }
+    }
 
     /// Tests if bytes after encryption are approximately equally distributed.
     #[test]
@@ -365,7 +2334,15 @@ mod tests {
         let settings = CryptSettings::default_for_testing();
         let mut data = Cursor::new((0..len).map(|_| 255 / 2).collect::<Vec<u8>>());
         let mut out = Cursor::new(Vec::new());
-        encrypt(&mut data, &mut out, "passwd", settings, Progress::new()).unwrap();
+        encrypt(
+            &mut data,
+            &mut out,
+            "passwd",
+            b"",
+            settings,
+            Progress::new(),
+        )
+        .unwrap();
 
         for byte in out.into_inner() {
             cnt[byte as usize] += 1;
@@ -373,7 +2350,7 @@ mod tests {
 
         let mut max_dist = 0;
         for (prev, current) in iter::zip(cnt.iter(), cnt.iter().skip(1)) {
-            let tmp = (*prev as usize).abs_diff(*current as usize);
+            let tmp = prev.abs_diff(*current);
             if tmp > max_dist {
                 max_dist = tmp;
             }
@@ -386,12 +2363,13 @@ mod tests {
         let mut hasher1 = Sha3_512::new();
         let mut data: Vec<u8> = (0..10_u64.pow(1)).map(|b| b as u8).collect();
 
-        let mut s = Stream::new(
+        let mut s = Stream::<Sha3_512>::new(
             "passwd",
             Vec::from([0_u8; 64]),
             CryptSettings::default_for_testing(),
             Progress::new(),
-        );
+        )
+        .unwrap();
 
         s.apply_with_hash(&mut data, &mut hasher1, Progress::new());
 
@@ -0,0 +1,320 @@
+//! `Format::Chunked`, which splits the ciphertext into chunks of `CHUNK_LEN` bytes,
+//! each followed by its own tag. A tag is bound to the index of its chunk and to
+//! whether it is the last one, so that corrupted, reordered and missing chunks are
+//! detected individually. With `KeystreamMode::Counter` every chunk can be
+//! decrypted on its own. Chunks are never pipelined, see `cipher::encrypt_pipelined`.
+
+use std::io::{Read, Write};
+
+use zeroize::Zeroizing;
+
+use super::pipeline::read_chunk;
+use super::{tags_equal, CryptSettings, KeystreamMode, Stream, SubKeys, ETM_PREFIX_LEN};
+use crate::error::{Error, Result};
+use crate::hash::{BalloonHash, Kmac256};
+use crate::progress::Progress;
+use crate::secret::Key;
+
+/// Length of the plaintext of every chunk but the last one.
+pub(crate) const CHUNK_LEN: usize = 1 << 20;
+pub(super) const TAG_LEN: usize = 64;
+/// Length of a chunk including its tag.
+pub(super) const SEALED_LEN: usize = CHUNK_LEN + TAG_LEN;
+
+/// Returns `Error::InvalidSettings` unless the keystream of `settings` can be
+/// computed for every chunk on its own.
+pub(super) fn check_chunked(settings: &CryptSettings) -> Result<()> {
+    match settings.mode {
+        KeystreamMode::Counter => Ok(()),
+        KeystreamMode::Sequential => Err(Error::InvalidSettings(
+            "`Format::Chunked` requires `KeystreamMode::Counter`".to_string(),
+        )),
+    }
+}
+
+/// Computes the tag of chunk `index` from `mac`, which already covers the header.
+pub(super) fn chunk_tag(mac: &Kmac256, index: u64, last: bool, ciphertext: &[u8]) -> [u8; 64] {
+    let mut mac = mac.clone();
+    mac.update(index.to_le_bytes());
+    mac.update([last as u8]);
+    mac.update(ciphertext);
+    mac.finalize()
+}
+
+/// Number of chunks and length of the plaintext of a stream whose chunks
+/// take up `len` bytes after the prefix.
+/// Returns `Error::CorruptedChunk` if the last chunk is too short to be one.
+pub(super) fn layout(len: u64) -> Result<(u64, u64)> {
+    let count = len.div_ceil(SEALED_LEN as u64).max(1);
+    let last = len - (count - 1) * SEALED_LEN as u64;
+    // Only a stream consisting of a single chunk can end with an empty one
+    let min = if count == 1 { TAG_LEN } else { TAG_LEN + 1 };
+    if last < min as u64 {
+        return Err(Error::CorruptedChunk(count - 1));
+    }
+    Ok((count, len - count * TAG_LEN as u64))
+}
+
+/// `encrypt` for `Format::Chunked`. Keys and prefix are the same as for
+/// `Format::EncryptThenMac`, the salt of the `Balloon` is returned as is.
+pub(super) fn encrypt<D: BalloonHash, R: Read, W: Write>(
+    source: &mut R,
+    dest: &mut W,
+    key: impl Key,
+    header: &[u8],
+    settings: CryptSettings,
+    salts: &mut dyn FnMut() -> [u8; 64],
+    prog: Progress,
+) -> Result<[u8; 64]> {
+    check_chunked(&settings)?;
+
+    prog.set_state("Deriving Password".to_string());
+    let kdf_salt = salts();
+    let keys = SubKeys::derive(key, &kdf_salt, &settings, prog.clone())?;
+
+    let salt = salts();
+    let mut stream = Stream::<D>::new(&keys.encryption[..], salt.to_vec(), settings, prog.clone())?;
+
+    prog.set_state("Encrypting".to_string());
+
+    let mut prefix = [0_u8; ETM_PREFIX_LEN];
+    prefix[..64].copy_from_slice(&kdf_salt);
+    prefix[64..128].copy_from_slice(&keys.check);
+    prefix[128..].copy_from_slice(&keys.header_tag(header, &salt, settings.format));
+    dest.write_all(&prefix)?;
+
+    let mac = keys.data_mac(header, settings.format);
+
+    // The next chunk is read ahead, since the tag depends on whether it is empty.
+    let mut chunk = Zeroizing::new(vec![0_u8; CHUNK_LEN]);
+    let mut next = Zeroizing::new(vec![0_u8; CHUNK_LEN]);
+    let mut len = read_chunk(source, &mut chunk)?;
+    let mut index = 0;
+    loop {
+        let next_len = match len {
+            CHUNK_LEN => read_chunk(source, &mut next)?,
+            _ => 0,
+        };
+        let last = next_len == 0;
+
+        stream.apply_with_salt(&mut chunk[..len], &mut [0_u8; 64], prog.clone());
+        dest.write_all(&chunk[..len])?;
+        dest.write_all(&chunk_tag(&mac, index, last, &chunk[..len]))?;
+
+        if last {
+            break;
+        }
+        std::mem::swap(&mut chunk, &mut next);
+        len = next_len;
+        index += 1;
+    }
+
+    Ok(salt)
+}
+
+/// `decrypt` for `Format::Chunked`, reading `source` only once. Every chunk is
+/// authenticated before it is decrypted and written to `dest`.
+pub(super) fn decrypt<D: BalloonHash, R: Read, W: Write>(
+    source: &mut R,
+    dest: &mut W,
+    key: impl Key,
+    salt: &[u8; 64],
+    header: &[u8],
+    settings: CryptSettings,
+    prog: Progress,
+) -> Result<()> {
+    check_chunked(&settings)?;
+
+    prog.set_state("Deriving Password".to_string());
+    let mut prefix = [0_u8; ETM_PREFIX_LEN];
+    if read_chunk(source, &mut prefix)? < ETM_PREFIX_LEN {
+        return Err(Error::Truncated);
+    }
+    let keys = SubKeys::from_prefix(key, &prefix, header, salt, &settings, prog.clone())?;
+
+    let mut stream = Stream::<D>::new(&keys.encryption[..], salt.to_vec(), settings, prog.clone())?;
+
+    prog.set_state("Decrypting".to_string());
+    let mac = keys.data_mac(header, settings.format);
+
+    let mut sealed = Zeroizing::new(vec![0_u8; SEALED_LEN]);
+    let mut index = 0;
+    loop {
+        let n = read_chunk(source, &mut sealed)?;
+        if n == 0 {
+            return Err(Error::Truncated);
+        }
+        let Some(len) = n.checked_sub(TAG_LEN) else {
+            return Err(Error::CorruptedChunk(index));
+        };
+        let (ciphertext, tag) = sealed[..n].split_at_mut(len);
+
+        let last = if tags_equal(tag, &chunk_tag(&mac, index, false, ciphertext)) {
+            false
+        } else if tags_equal(tag, &chunk_tag(&mac, index, true, ciphertext)) {
+            true
+        } else {
+            return Err(Error::CorruptedChunk(index));
+        };
+
+        stream.apply_with_salt(&mut *ciphertext, &mut [0_u8; 64], prog.clone());
+        dest.write_all(ciphertext)?;
+
+        if last {
+            // Anything after the last chunk has been appended
+            return match source.read(&mut [0_u8; 1])? {
+                0 => Ok(()),
+                _ => Err(Error::CorruptedChunk(index + 1)),
+            };
+        }
+        index += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::cipher::{decrypt, decrypt_single_pass, encrypt_single_pass, Format};
+
+    fn settings() -> CryptSettings {
+        CryptSettings {
+            format: Format::Chunked,
+            mode: KeystreamMode::Counter,
+            ..CryptSettings::default_for_testing()
+        }
+    }
+
+    fn encrypted(data: &[u8]) -> (Vec<u8>, [u8; 64]) {
+        let mut ciphertext = Vec::new();
+        let salt = encrypt_single_pass(
+            &mut &data[..],
+            &mut ciphertext,
+            "password",
+            b"header",
+            settings(),
+            Progress::new(),
+        )
+        .unwrap();
+        (ciphertext, salt)
+    }
+
+    /// Decrypts `ciphertext` in one pass, returning what has been written as well.
+    fn decrypted(ciphertext: &[u8], salt: &[u8; 64]) -> (Result<()>, Vec<u8>) {
+        let mut res = Vec::new();
+        let status = decrypt_single_pass(
+            &mut &ciphertext[..],
+            &mut res,
+            "password",
+            salt,
+            b"header",
+            settings(),
+            Progress::new(),
+        );
+        (status, res)
+    }
+
+    #[test]
+    fn encrypt_and_decrypt_chunks() {
+        for len in [0, 1, CHUNK_LEN, CHUNK_LEN + 100] {
+            let data: Vec<u8> = (0..len).map(|b| (b * 7) as u8).collect();
+            let (ciphertext, salt) = encrypted(&data);
+            let (count, plaintext_len) =
+                layout((ciphertext.len() - ETM_PREFIX_LEN) as u64).unwrap();
+            assert_eq!(count, len.div_ceil(CHUNK_LEN).max(1) as u64);
+            assert_eq!(plaintext_len, len as u64);
+
+            let (status, res) = decrypted(&ciphertext, &salt);
+            status.unwrap();
+            assert_eq!(res, data);
+
+            let mut res = Vec::new();
+            decrypt(
+                &mut Cursor::new(&ciphertext),
+                &mut res,
+                "password",
+                &salt,
+                b"header",
+                settings(),
+                Progress::new(),
+            )
+            .unwrap();
+            assert_eq!(res, data);
+        }
+    }
+
+    #[test]
+    fn damage_is_pinpointed() {
+        let data: Vec<u8> = (0..2 * CHUNK_LEN + 10).map(|b| (b * 3) as u8).collect();
+        let (ciphertext, salt) = encrypted(&data);
+        let chunk = |index: usize| ETM_PREFIX_LEN + index * SEALED_LEN;
+
+        // Chunks in front of the damaged one are written nonetheless
+        let mut corrupted = ciphertext.clone();
+        corrupted[chunk(1) + 5] ^= 1;
+        let (status, res) = decrypted(&corrupted, &salt);
+        assert!(matches!(status, Err(Error::CorruptedChunk(1))));
+        assert_eq!(res, data[..CHUNK_LEN]);
+
+        let mut reordered = ciphertext.clone();
+        reordered[chunk(0)..chunk(2)].rotate_left(SEALED_LEN);
+        let (status, res) = decrypted(&reordered, &salt);
+        assert!(matches!(status, Err(Error::CorruptedChunk(0))));
+        assert!(res.is_empty());
+
+        let (status, _) = decrypted(&ciphertext[..chunk(2)], &salt);
+        assert!(matches!(status, Err(Error::Truncated)));
+        let (status, _) = decrypted(&ciphertext[..chunk(2) + 20], &salt);
+        assert!(matches!(status, Err(Error::CorruptedChunk(2))));
+        let (status, _) = decrypted(&ciphertext[..chunk(1) + 20], &salt);
+        assert!(matches!(status, Err(Error::CorruptedChunk(1))));
+
+        // Appended data becomes part of the last chunk
+        let mut appended = ciphertext.clone();
+        appended.push(0);
+        let (status, res) = decrypted(&appended, &salt);
+        assert!(matches!(status, Err(Error::CorruptedChunk(2))));
+        assert_eq!(res, data[..2 * CHUNK_LEN]);
+
+        // The salt is authenticated along with the header
+        let mut tampered = salt;
+        tampered[10] ^= 1;
+        let (status, res) = decrypted(&ciphertext, &tampered);
+        assert!(matches!(status, Err(Error::HeaderTampered)));
+        assert!(res.is_empty());
+    }
+
+    #[test]
+    fn layout_rejects_short_last_chunk() {
+        assert_eq!(layout(TAG_LEN as u64).unwrap(), (1, 0));
+        assert!(matches!(layout(10), Err(Error::CorruptedChunk(0))));
+        let full = SEALED_LEN as u64;
+        assert_eq!(
+            layout(full + TAG_LEN as u64 + 1).unwrap(),
+            (2, CHUNK_LEN as u64 + 1)
+        );
+        for last in [1, TAG_LEN as u64] {
+            assert!(matches!(layout(full + last), Err(Error::CorruptedChunk(1))));
+        }
+    }
+
+    #[test]
+    fn requires_counter_mode() {
+        let settings = CryptSettings {
+            mode: KeystreamMode::Sequential,
+            ..settings()
+        };
+        assert!(matches!(
+            encrypt_single_pass(
+                &mut &b"data"[..],
+                &mut Vec::new(),
+                "password",
+                b"header",
+                settings,
+                Progress::new(),
+            ),
+            Err(Error::InvalidSettings(_))
+        ));
+    }
+}
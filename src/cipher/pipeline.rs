@@ -0,0 +1,170 @@
+//! Pipelined version of the `Stream` copy functions, used by `encrypt_pipelined` and
+//! `decrypt_pipelined`.
+//! The keystream is computed ahead in one thread and the output is hashed in another,
+//! while the calling thread reads, applies the keystream and writes.
+
+use std::io::{self, Read, Write};
+use std::sync::mpsc;
+use std::thread;
+
+use zeroize::Zeroizing;
+
+use super::{xor_in_place, xor_into_salt, Stream};
+use crate::hash::BalloonHash;
+use crate::progress::Progress;
+
+/// Size of the chunks passed between threads.
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Number of chunks of keystream computed ahead.
+const DEPTH: usize = 4;
+
+/// Consumer of the output, e.g. a *MAC*, run in its own thread.
+pub(super) type Absorb<'a> = &'a mut (dyn FnMut(&[u8]) + Send);
+
+impl<D: BalloonHash> Stream<D> {
+    /// Fills `buffer` with the next bytes of the keystream, without touching the salt.
+    fn fill_keystream(&mut self, buffer: &mut [u8], prog: &Progress) {
+        let mut buffer = buffer;
+        while !buffer.is_empty() {
+            let n = self.apply_block(buffer, prog);
+            buffer = &mut buffer[n..];
+        }
+    }
+
+    /// Like `copy_and_apply_with_salt`, but the keystream is computed ahead in a
+    /// separate thread and, if provided, `absorb` is fed the output in another one.
+    /// Consumes the stream, since it runs ahead of the data.
+    /// Returns the number of bytes copied.
+    pub(super) fn pipelined_copy(
+        self,
+        src: &mut impl Read,
+        dest: &mut impl Write,
+        salt: &mut [u8; 64],
+        absorb: Option<Absorb>,
+        prog: Progress,
+    ) -> io::Result<u64> {
+        let offset = self.salt_ptr as u64;
+        thread::scope(|scope| {
+            // Both threads stop once their channel is closed, which happens
+            // when this closure returns, even if it fails.
+            let (keystream_tx, keystream_rx) = mpsc::sync_channel(DEPTH);
+            let mut stream = self;
+            scope.spawn(move || loop {
+                let mut keystream = Zeroizing::new(vec![0_u8; CHUNK_SIZE]);
+                stream.fill_keystream(&mut keystream, &prog);
+                if keystream_tx.send(keystream).is_err() {
+                    break;
+                }
+            });
+
+            let (output_tx, output_rx) = mpsc::sync_channel::<Zeroizing<Vec<u8>>>(DEPTH);
+            let hasher = absorb.map(|absorb| {
+                scope.spawn(move || {
+                    for chunk in output_rx {
+                        absorb(&chunk);
+                    }
+                })
+            });
+
+            let mut copied = 0_u64;
+            loop {
+                let mut buffer = Zeroizing::new(vec![0_u8; CHUNK_SIZE]);
+                let n = read_chunk(src, &mut buffer)?;
+                if n == 0 {
+                    break;
+                }
+                let Ok(keystream) = keystream_rx.recv() else {
+                    return Err(io::Error::other("keystream thread failed"));
+                };
+                xor_in_place(&mut buffer[..n], &keystream[..n]);
+                xor_into_salt(salt, offset + copied, &buffer[..n]);
+                dest.write_all(&buffer[..n])?;
+                copied += n as u64;
+
+                if hasher.is_some() {
+                    buffer.truncate(n);
+                    if output_tx.send(buffer).is_err() {
+                        return Err(io::Error::other("hashing thread failed"));
+                    }
+                }
+                if n < CHUNK_SIZE {
+                    break;
+                }
+            }
+
+            drop(output_tx);
+            if let Some(hasher) = hasher {
+                if hasher.join().is_err() {
+                    return Err(io::Error::other("hashing thread failed"));
+                }
+            }
+            Ok(copied)
+        })
+    }
+}
+
+/// Reads until `buffer` is full or `src` is exhausted, so that only the last
+/// chunk is shorter than `CHUNK_SIZE`. Returns the number of bytes read.
+pub(super) fn read_chunk(src: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buffer.len() {
+        match src.read(&mut buffer[n..]) {
+            Ok(0) => break,
+            Ok(read) => n += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use sha3::{Digest, Sha3_512};
+
+    use super::*;
+    use crate::cipher::CryptSettings;
+
+    /// Pipelined and sequential copies have to produce the same output, salt and hash,
+    /// in particular around chunk boundaries.
+    #[test]
+    fn pipelined_matches_sequential() {
+        let settings = CryptSettings::default_for_testing();
+        for len in [0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, 2 * CHUNK_SIZE + 100] {
+            let data: Vec<u8> = (0..len).map(|b| (b * 7) as u8).collect();
+            let new_stream =
+                || Stream::<Sha3_512>::new("password", vec![1, 2, 3], settings, Progress::new());
+
+            let mut expected = Vec::new();
+            let mut expected_salt = [0_u8; 64];
+            new_stream()
+                .unwrap()
+                .copy_and_apply_with_salt(
+                    &mut &data[..],
+                    &mut expected,
+                    &mut expected_salt,
+                    Progress::new(),
+                )
+                .unwrap();
+
+            let mut res = Vec::new();
+            let mut salt = [0_u8; 64];
+            let mut hash = Sha3_512::new();
+            let copied = new_stream()
+                .unwrap()
+                .pipelined_copy(
+                    &mut &data[..],
+                    &mut res,
+                    &mut salt,
+                    Some(&mut |chunk: &[u8]| hash.update(chunk)),
+                    Progress::new(),
+                )
+                .unwrap();
+
+            assert_eq!(copied, len as u64);
+            assert_eq!(res, expected);
+            assert_eq!(salt, expected_salt);
+            assert_eq!(hash.finalize()[..], Sha3_512::digest(&expected)[..]);
+        }
+    }
+}
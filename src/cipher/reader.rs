@@ -0,0 +1,446 @@
+//! `DecryptReader`, which decrypts a stream in `Format::EncryptThenMac`,
+//! `Format::SinglePass` or `Format::Chunked` on demand and allows seeking within it.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use blake2::Blake2b512;
+use sha2::Sha512;
+use sha3::Sha3_512;
+use zeroize::Zeroizing;
+
+use super::chunked::{self, chunk_tag, CHUNK_LEN, SEALED_LEN, TAG_LEN};
+use super::pipeline::read_chunk;
+use super::{tags_equal, verify_mac, CryptSettings, Format, Stream, SubKeys, ETM_PREFIX_LEN};
+use crate::error::{Error, Result};
+use crate::hash::{BalloonHash, HashFunction, Kmac256};
+use crate::progress::Progress;
+use crate::secret::Key;
+
+/// `Stream` with the hash function erased, so that it can be chosen at runtime.
+trait Keystream: Send {
+    fn apply(&mut self, data: &mut [u8], prog: &Progress);
+    fn seek(&mut self, offset: u64, prog: &Progress) -> io::Result<()>;
+}
+
+impl<D: BalloonHash> Keystream for Stream<D> {
+    fn apply(&mut self, mut data: &mut [u8], prog: &Progress) {
+        while !data.is_empty() {
+            let n = self.apply_block(data, prog);
+            data = &mut data[n..];
+        }
+    }
+
+    fn seek(&mut self, offset: u64, prog: &Progress) -> io::Result<()> {
+        Stream::seek(self, offset, prog)
+    }
+}
+
+/// Creates a `Stream` using `D` for the `Balloon`.
+fn boxed<D: BalloonHash + 'static>(
+    key: &[u8],
+    salt: Vec<u8>,
+    settings: CryptSettings,
+    prog: Progress,
+) -> Result<Box<dyn Keystream>> {
+    Ok(Box::new(Stream::<D>::new(key, salt, settings, prog)?))
+}
+
+/// Chunks of a stream in `Format::Chunked`, which are authenticated one by one.
+struct Chunks {
+    /// *MAC* covering the header, see `chunk_tag`.
+    mac: Kmac256,
+    count: u64,
+    /// Index and plaintext of the chunk read last.
+    current: Option<(u64, Zeroizing<Vec<u8>>)>,
+}
+
+impl Chunks {
+    /// Authenticates and decrypts chunk `index` unless it is the current one.
+    /// Returns its plaintext.
+    fn load<R: Read + Seek>(
+        &mut self,
+        source: &mut R,
+        stream: &mut dyn Keystream,
+        index: u64,
+        prog: &Progress,
+    ) -> Result<&[u8]> {
+        if !matches!(&self.current, Some((current, _)) if *current == index) {
+            self.current = None;
+            source.seek(SeekFrom::Start(
+                ETM_PREFIX_LEN as u64 + index * SEALED_LEN as u64,
+            ))?;
+            let mut sealed = Zeroizing::new(vec![0_u8; SEALED_LEN]);
+            let n = read_chunk(source, &mut sealed)?;
+            let Some(len) = n.checked_sub(TAG_LEN) else {
+                return Err(Error::CorruptedChunk(index));
+            };
+            let (ciphertext, tag) = sealed[..n].split_at_mut(len);
+
+            let last = index + 1 == self.count;
+            if !tags_equal(tag, &chunk_tag(&self.mac, index, last, ciphertext)) {
+                // A chunk that is not the last one can only end up at the end by truncation
+                if last && tags_equal(tag, &chunk_tag(&self.mac, index, false, ciphertext)) {
+                    return Err(Error::Truncated);
+                }
+                return Err(Error::CorruptedChunk(index));
+            }
+
+            stream.seek(index * CHUNK_LEN as u64, prog)?;
+            stream.apply(ciphertext, prog);
+            sealed.truncate(len);
+            self.current = Some((index, sealed));
+        }
+        Ok(self
+            .current
+            .as_ref()
+            .map_or(&[], |(_, plaintext)| plaintext))
+    }
+}
+
+/// Reader over the plaintext of a stream created by `encrypt`, decrypting
+/// only the parts that are read.
+///
+/// With `KeystreamMode::Counter` any part can be read without computing
+/// the keystream in front of it. Otherwise seeking forward computes the
+/// skipped keystream and seeking backwards fails.
+pub struct DecryptReader<R> {
+    source: R,
+    stream: Box<dyn Keystream>,
+    len: u64,
+    pos: u64,
+    /// Only for `Format::Chunked`.
+    chunks: Option<Chunks>,
+    prog: Progress,
+}
+
+impl<R: Read + Seek> DecryptReader<R> {
+    /// Authenticates `source`, which must hold exactly the output of `encrypt`,
+    /// and creates the `Balloon`. This reads the whole ciphertext once but
+    /// does not decrypt any of it.
+    ///
+    /// `source` is read again when reading from the returned reader, which
+    /// trusts it not to have changed since. Copy sources that may be modified
+    /// by someone else first, or use `Format::Chunked`.
+    ///
+    /// For `Format::Chunked` only password and `header` are checked here.
+    /// Every chunk is authenticated when it is first read instead, so reading
+    /// fails with `Error::CorruptedChunk` or `Error::Truncated` wrapped in an
+    /// `io::Error` of kind `InvalidData`.
+    ///
+    /// Returns `Error::InvalidSettings` for `Format::Legacy` and
+    /// `Format::AuthenticatedHeader`, since they can only be authenticated
+    /// after decrypting everything.
+    pub fn new(
+        mut source: R,
+        key: impl Key,
+        salt: &[u8; 64],
+        header: &[u8],
+        settings: CryptSettings,
+        prog: Progress,
+    ) -> Result<Self> {
+        let (keys, salt, len, chunks) = match settings.format {
+            Format::EncryptThenMac | Format::SinglePass => {
+                let (keys, salt, len) =
+                    verify_mac(&mut source, key, salt, header, settings, &prog)?;
+                (keys, salt, len, None)
+            }
+            Format::Chunked => {
+                chunked::check_chunked(&settings)?;
+                let total = source.seek(SeekFrom::End(0))?;
+                let Some(sealed) = total.checked_sub(ETM_PREFIX_LEN as u64) else {
+                    return Err(Error::Truncated);
+                };
+                let (count, len) = chunked::layout(sealed)?;
+
+                prog.set_state("Deriving Password".to_string());
+                let mut prefix = [0_u8; ETM_PREFIX_LEN];
+                source.rewind()?;
+                source.read_exact(&mut prefix)?;
+                let keys =
+                    SubKeys::from_prefix(key, &prefix, header, salt, &settings, prog.clone())?;
+                let chunks = Chunks {
+                    mac: keys.data_mac(header, settings.format),
+                    count,
+                    current: None,
+                };
+                (keys, *salt, len, Some(chunks))
+            }
+            Format::Legacy | Format::AuthenticatedHeader => {
+                return Err(Error::InvalidSettings(
+                    "random access requires `Format::EncryptThenMac` or newer".to_string(),
+                ))
+            }
+        };
+
+        let new_stream = match settings.hash {
+            HashFunction::Sha3_512 => boxed::<Sha3_512>,
+            HashFunction::Blake2b512 => boxed::<Blake2b512>,
+            HashFunction::Sha512 => boxed::<Sha512>,
+        };
+        let stream = new_stream(&keys.encryption, salt.to_vec(), settings, prog.clone())?;
+
+        prog.set_state("Decrypting".to_string());
+        source.seek(SeekFrom::Start(ETM_PREFIX_LEN as u64))?;
+        Ok(Self {
+            source,
+            stream,
+            len,
+            pos: 0,
+            chunks,
+            prog,
+        })
+    }
+
+    /// Length of the plaintext.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<R: Read + Seek> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(chunks) = &mut self.chunks {
+            if self.pos >= self.len {
+                return Ok(0);
+            }
+            let index = self.pos / CHUNK_LEN as u64;
+            let plaintext = chunks
+                .load(&mut self.source, &mut *self.stream, index, &self.prog)
+                .map_err(|e| match e {
+                    Error::Io(e) => e,
+                    e => io::Error::new(io::ErrorKind::InvalidData, e),
+                })?;
+            let offset = (self.pos % CHUNK_LEN as u64) as usize;
+            let remaining = self.len - self.pos;
+            let n = buf
+                .len()
+                .min(plaintext.len().saturating_sub(offset))
+                .min(remaining.try_into().unwrap_or(usize::MAX));
+            buf[..n].copy_from_slice(&plaintext[offset..offset + n]);
+            self.pos += n as u64;
+            return Ok(n);
+        }
+
+        // Stop in front of the tag
+        let remaining = self.len.saturating_sub(self.pos);
+        let max = buf.len().min(remaining.try_into().unwrap_or(usize::MAX));
+        let n = self.source.read(&mut buf[..max])?;
+        self.stream.apply(&mut buf[..n], &self.prog);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Seek> Seek for DecryptReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        let Some(pos) = pos else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek before start of file",
+            ));
+        };
+        // Seeking past the end is allowed, reading there returns nothing
+        if self.chunks.is_none() {
+            let offset = pos.min(self.len);
+            self.stream.seek(offset, &self.prog)?;
+            self.source
+                .seek(SeekFrom::Start(ETM_PREFIX_LEN as u64 + offset))?;
+        }
+        self.pos = pos;
+        Ok(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::cipher::{decrypt, encrypt, KeystreamMode};
+
+    fn encrypted(data: &[u8], settings: CryptSettings) -> (Cursor<Vec<u8>>, [u8; 64]) {
+        let mut ciphertext = Cursor::new(Vec::new());
+        let salt = encrypt(
+            &mut Cursor::new(data.to_vec()),
+            &mut ciphertext,
+            "password",
+            b"header",
+            settings,
+            Progress::new(),
+        )
+        .unwrap();
+        (ciphertext, salt)
+    }
+
+    fn reader<'a>(
+        ciphertext: &'a mut Cursor<Vec<u8>>,
+        salt: &[u8; 64],
+        settings: CryptSettings,
+    ) -> Result<DecryptReader<&'a mut Cursor<Vec<u8>>>> {
+        DecryptReader::new(
+            ciphertext,
+            "password",
+            salt,
+            b"header",
+            settings,
+            Progress::new(),
+        )
+    }
+
+    #[test]
+    fn reads_any_range() {
+        let settings = CryptSettings {
+            mode: KeystreamMode::Counter,
+            ..CryptSettings::default_for_testing()
+        };
+        let data: Vec<u8> = (0..1000_u32).map(|b| (b * 7) as u8).collect();
+        let (mut ciphertext, salt) = encrypted(&data, settings);
+
+        let mut expected = Vec::new();
+        decrypt(
+            &mut ciphertext,
+            &mut expected,
+            "password",
+            &salt,
+            b"header",
+            settings,
+            Progress::new(),
+        )
+        .unwrap();
+        assert_eq!(expected, data);
+
+        let mut reader = reader(&mut ciphertext, &salt, settings).unwrap();
+        assert_eq!(reader.len(), data.len() as u64);
+        for (start, len) in [(900, 100), (0, 10), (63, 2), (500, 300), (64, 64), (999, 1)] {
+            reader.seek(SeekFrom::Start(start)).unwrap();
+            let mut res = vec![0_u8; len];
+            reader.read_exact(&mut res).unwrap();
+            assert_eq!(res, data[start as usize..start as usize + len]);
+        }
+
+        reader.seek(SeekFrom::End(-5)).unwrap();
+        let mut res = Vec::new();
+        reader.read_to_end(&mut res).unwrap();
+        assert_eq!(res, data[995..]);
+        assert_eq!(reader.seek(SeekFrom::Current(10)).unwrap(), 1010);
+        assert_eq!(reader.read(&mut [0; 10]).unwrap(), 0);
+        assert!(reader.seek(SeekFrom::Current(-2000)).is_err());
+    }
+
+    #[test]
+    fn sequential_only_seeks_forward() {
+        let settings = CryptSettings::default_for_testing();
+        let data: Vec<u8> = (0..300_u32).map(|b| b as u8).collect();
+        let (mut ciphertext, salt) = encrypted(&data, settings);
+
+        let mut reader = reader(&mut ciphertext, &salt, settings).unwrap();
+        let mut res = vec![0_u8; 10];
+        reader.read_exact(&mut res).unwrap();
+        assert_eq!(res, data[..10]);
+        reader.seek(SeekFrom::Start(130)).unwrap();
+        reader.read_exact(&mut res).unwrap();
+        assert_eq!(res, data[130..140]);
+        let err = reader.seek(SeekFrom::Start(0)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn authenticates_chunks_when_read() {
+        let settings = CryptSettings {
+            format: Format::Chunked,
+            mode: KeystreamMode::Counter,
+            ..CryptSettings::default_for_testing()
+        };
+        let data: Vec<u8> = (0..2 * CHUNK_LEN + 10).map(|b| (b * 5) as u8).collect();
+        let (mut ciphertext, salt) = encrypted(&data, settings);
+        ciphertext.get_mut()[ETM_PREFIX_LEN + 3] ^= 1;
+
+        let mut chunks = reader(&mut ciphertext, &salt, settings).unwrap();
+        assert_eq!(chunks.len(), data.len() as u64);
+        for start in [2 * CHUNK_LEN + 5, CHUNK_LEN, CHUNK_LEN + 100] {
+            chunks.seek(SeekFrom::Start(start as u64)).unwrap();
+            let mut res = vec![0_u8; 5];
+            chunks.read_exact(&mut res).unwrap();
+            assert_eq!(res, data[start..start + 5]);
+        }
+
+        // Only the corrupted chunk can not be read
+        chunks.rewind().unwrap();
+        let err = chunks.read(&mut [0; 10]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = err.into_inner().unwrap().downcast::<Error>().unwrap();
+        assert!(matches!(*err, Error::CorruptedChunk(0)));
+
+        // Cut off after the second chunk
+        ciphertext
+            .get_mut()
+            .truncate(ETM_PREFIX_LEN + 2 * SEALED_LEN);
+        let mut truncated = reader(&mut ciphertext, &salt, settings).unwrap();
+        assert_eq!(truncated.len(), 2 * CHUNK_LEN as u64);
+        truncated.seek(SeekFrom::End(-1)).unwrap();
+        let err = truncated.read(&mut [0; 1]).unwrap_err();
+        let err = err.into_inner().unwrap().downcast::<Error>().unwrap();
+        assert!(matches!(*err, Error::Truncated));
+    }
+
+    #[test]
+    fn detects_truncation_at_every_cut() {
+        let settings = CryptSettings {
+            format: Format::Chunked,
+            mode: KeystreamMode::Counter,
+            ..CryptSettings::default_for_testing()
+        };
+        let data: Vec<u8> = (0..CHUNK_LEN + 1).map(|b| (b * 3) as u8).collect();
+        let (ciphertext, salt) = encrypted(&data, settings);
+        let full = ciphertext.get_ref().len();
+
+        let read_all = |len: usize| {
+            let mut truncated = Cursor::new(ciphertext.get_ref()[..len].to_vec());
+            let mut reader = reader(&mut truncated, &salt, settings)?;
+            let mut res = Vec::new();
+            reader.read_to_end(&mut res)?;
+            Ok::<_, Error>(res)
+        };
+        assert_eq!(read_all(full).unwrap(), data);
+        // The last chunk holds a single byte, so every cut within it crosses the tag
+        for len in full - TAG_LEN..full {
+            assert!(
+                matches!(read_all(len), Err(Error::CorruptedChunk(1))),
+                "cut at {len}"
+            );
+        }
+    }
+
+    #[test]
+    fn authenticates_before_reading() {
+        let settings = CryptSettings {
+            mode: KeystreamMode::Counter,
+            ..CryptSettings::default_for_testing()
+        };
+        let (mut ciphertext, salt) = encrypted(b"Secret message", settings);
+        ciphertext.get_mut()[ETM_PREFIX_LEN] ^= 1;
+        assert!(matches!(
+            reader(&mut ciphertext, &salt, settings),
+            Err(Error::Corrupted)
+        ));
+
+        let legacy = CryptSettings {
+            format: Format::AuthenticatedHeader,
+            ..settings
+        };
+        let (mut ciphertext, salt) = encrypted(b"Secret message", legacy);
+        assert!(matches!(
+            reader(&mut ciphertext, &salt, legacy),
+            Err(Error::InvalidSettings(_))
+        ));
+    }
+}
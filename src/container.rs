@@ -2,16 +2,27 @@
 
 use rand::prelude::*;
 use rand_chacha::ChaCha20Rng;
+use std::collections::HashMap;
 use std::fs;
 use std::io::{self, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
-use zip::{result::ZipResult, write::FileOptions, ZipArchive, ZipWriter};
-use zip::{CompressionMethod, DateTime};
+use std::path::{Component, Path, PathBuf};
+use zip::{read::ZipFile, result::ZipError, CompressionMethod, ZipArchive};
 
-use crate::cipher::{decrypt, decrypt_salt, encrypt, CryptSettings};
+use crate::cipher::{
+    check_single_pass, decrypt_with, encrypt_single_pass, encrypt_with_salts, gen_salt, unwrap_key,
+    wrap_key, CryptSettings, DecryptReader, Format, Preset, WRAPPED_KEY_LEN,
+};
+use crate::error::{Error, Result};
+use crate::kdf::{KdfSettings, Pepper};
 use crate::progress::Progress;
+use crate::secret::{Key, Peppered};
 
 use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+mod writer;
+
+use writer::{overwrite_file, StreamingZipWriter};
 
 #[derive(PartialEq, Eq, Serialize, Deserialize, Debug)]
 pub struct ContainerMetadata {
@@ -19,99 +30,971 @@ pub struct ContainerMetadata {
     settings: CryptSettings,
 }
 
+impl ContainerMetadata {
+    /// Version of the crate that created the container.
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    pub fn settings(&self) -> &CryptSettings {
+        &self.settings
+    }
+
+    /// Preset the container was created with, if any. Only returned if the
+    /// settings actually match the preset, apart from `peppered` and `wrapped_key`,
+    /// since the label is stored next to them and could be anything.
+    pub fn preset(&self) -> Option<Preset> {
+        let preset = self.settings.preset?;
+        let expected = CryptSettings {
+            peppered: self.settings.peppered,
+            wrapped_key: self.settings.wrapped_key,
+            ..preset.settings()
+        };
+        (self.settings == expected).then_some(preset)
+    }
+}
+
+/// Limits on the `CryptSettings` of a container that is about to be decrypted.
+/// Since the settings are read from the container itself, a crafted container
+/// could otherwise demand arbitrary amounts of memory and time.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DecryptPolicy {
+    /// Maximum size of the `Balloon` buffer in bytes.
+    pub max_memory: u64,
+    pub max_t_cost: u64,
+    pub max_step_delta: u64,
+    /// Maximum number of lanes of the `Balloon`. Every lane has its own hasher
+    /// state and job, which `max_memory` does not account for.
+    pub max_p_cost: u64,
+    /// Maximum number of passes of a memory-hard key derivation.
+    pub max_kdf_t_cost: u64,
+    /// Maximum parallelism `p` of scrypt, which multiplies its running time.
+    pub max_kdf_p: u64,
+    pub max_pbkdf2_rounds: u64,
+    /// Where decrypted data is kept until it has been authenticated.
+    pub staging: Staging,
+    /// Decrypts with the keystream computed in a separate thread,
+    /// see `cipher::decrypt_pipelined`.
+    pub pipelined: bool,
+}
+
+/// Where decrypted data is kept until its MAC has been verified.
+/// Only applies to formats that authenticate data while decrypting it,
+/// `Format::EncryptThenMac` never writes unauthenticated data. It reads `data.dat`
+/// twice though, first to verify the MAC and then to decrypt it, so for it the
+/// ciphertext is staged instead, so that the source can not change in between.
+/// `Format::Chunked` only writes authenticated chunks, but truncation is only
+/// detected at the end, so staging withholds incomplete data.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Staging {
+    /// Data is written to `dest` while decrypting,
+    /// even if the MAC turns out not to match.
+    Direct,
+    /// Data is kept in memory, which is wiped afterwards.
+    /// Entries larger than `limit` bytes are rejected with `Error::PolicyViolation`.
+    Memory { limit: u64 },
+    /// Data is kept in an anonymous temporary file,
+    /// which is overwritten with random noise afterwards.
+    TempFile,
+}
+
+impl DecryptPolicy {
+    /// Policy that accepts any settings.
+    /// Only use this for containers from trusted sources.
+    pub fn unlimited() -> Self {
+        Self {
+            max_memory: u64::MAX,
+            max_t_cost: u64::MAX,
+            max_step_delta: u64::MAX,
+            max_p_cost: u64::MAX,
+            max_kdf_t_cost: u64::MAX,
+            max_kdf_p: u64::MAX,
+            max_pbkdf2_rounds: u64::MAX,
+            staging: Staging::Direct,
+            pipelined: false,
+        }
+    }
+
+    /// Checks `settings` against the policy without allocating anything.
+    /// `max_memory` applies to the `Balloon` and the key derivation separately.
+    /// Costs of zero are rejected with `Error::InvalidSettings`.
+    pub fn check(&self, settings: &CryptSettings) -> Result<()> {
+        let mut positive = vec![
+            ("s_cost", settings.s_cost),
+            ("t_cost", settings.t_cost),
+            ("step_delta", settings.step_delta),
+        ];
+        if let KdfSettings::Balloon(kdf) = settings.kdf {
+            positive.extend([
+                ("kdf_s_cost", kdf.s_cost),
+                ("kdf_t_cost", kdf.t_cost),
+                ("kdf_step_delta", kdf.step_delta),
+            ]);
+        }
+        if let Some((parameter, _)) = positive.iter().find(|(_, value)| *value == 0) {
+            return Err(Error::InvalidSettings(format!(
+                "{parameter} must be positive"
+            )));
+        }
+
+        let memory = settings.s_cost.saturating_mul(64);
+        let mut checks = vec![
+            ("memory", memory, self.max_memory),
+            ("t_cost", settings.t_cost, self.max_t_cost),
+            ("step_delta", settings.step_delta, self.max_step_delta),
+            ("p_cost", settings.p_cost, self.max_p_cost),
+        ];
+        match settings.kdf {
+            KdfSettings::Argon2id(kdf) => checks.extend([
+                ("kdf_memory", kdf.m_cost as u64 * 1024, self.max_memory),
+                ("kdf_t_cost", kdf.t_cost as u64, self.max_kdf_t_cost),
+            ]),
+            KdfSettings::Scrypt(kdf) => checks.extend([
+                ("kdf_memory", kdf.memory(), self.max_memory),
+                ("kdf_p", kdf.p as u64, self.max_kdf_p),
+            ]),
+            KdfSettings::Pbkdf2Sha512(kdf) => {
+                checks.push(("pbkdf2_rounds", kdf.rounds as u64, self.max_pbkdf2_rounds))
+            }
+            KdfSettings::Balloon(kdf) => checks.extend([
+                ("kdf_memory", kdf.s_cost.saturating_mul(64), self.max_memory),
+                ("kdf_t_cost", kdf.t_cost, self.max_kdf_t_cost),
+                ("kdf_step_delta", kdf.step_delta, self.max_step_delta),
+            ]),
+        }
+        for (parameter, value, limit) in checks {
+            if value > limit {
+                return Err(Error::PolicyViolation(PolicyViolation {
+                    parameter,
+                    value,
+                    limit,
+                }));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::default::Default for DecryptPolicy {
+    /// Allows up to 1 GiB of memory, comfortably above `CryptSettings::default()`.
+    fn default() -> Self {
+        Self {
+            max_memory: 1 << 30,
+            max_t_cost: 16,
+            max_step_delta: 16,
+            max_p_cost: 16,
+            max_kdf_t_cost: 64,
+            max_kdf_p: 16,
+            max_pbkdf2_rounds: 10_000_000,
+            staging: Staging::Direct,
+            pipelined: false,
+        }
+    }
+}
+
+/// Reason for `Error::PolicyViolation`, returned if the settings of a
+/// container exceed a `DecryptPolicy`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PolicyViolation {
+    pub parameter: &'static str,
+    pub value: u64,
+    pub limit: u64,
+}
+
+impl std::fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Settings exceed policy: {} is {} but at most {} is allowed",
+            self.parameter, self.value, self.limit
+        )
+    }
+}
+
+impl std::error::Error for PolicyViolation {}
+
+/// Metadata of a container together with its serialized form, which is
+/// authenticated as part of every entry.
+struct Header {
+    metadata: ContainerMetadata,
+    raw: Vec<u8>,
+}
+
+impl Header {
+    fn new(settings: CryptSettings) -> Self {
+        let metadata = ContainerMetadata {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            settings,
+        };
+        let raw = serde_json::to_vec(&metadata).unwrap();
+        Self { metadata, raw }
+    }
+
+    /// Parses `metadata.json`. If this fails for a container created by a
+    /// newer version `Error::UnsupportedVersion` is returned.
+    fn parse(raw: Vec<u8>) -> Result<Self> {
+        match serde_json::from_slice(&raw) {
+            Ok(metadata) => Ok(Self { metadata, raw }),
+            Err(e) => {
+                let version = serde_json::from_slice::<serde_json::Value>(&raw)
+                    .ok()
+                    .and_then(|value| value.get("version")?.as_str().map(String::from));
+                match version {
+                    Some(version) if is_newer(&version, env!("CARGO_PKG_VERSION")) => {
+                        Err(Error::UnsupportedVersion(version))
+                    }
+                    _ => Err(Error::InvalidContainer(format!(
+                        "Invalid `metadata.json` found: {e}"
+                    ))),
+                }
+            }
+        }
+    }
+
+    /// Data authenticated together with the entry at `prefix`, binding the
+    /// entry to both the metadata and its name.
+    fn associated_data(&self, prefix: &str) -> Vec<u8> {
+        let mut res = Vec::with_capacity(8 + self.raw.len() + prefix.len());
+        res.extend_from_slice(&(self.raw.len() as u64).to_le_bytes());
+        res.extend_from_slice(&self.raw);
+        res.extend_from_slice(prefix.as_bytes());
+        res
+    }
+}
+
+/// Compares two `major.minor.patch` versions, ignoring anything that isn't a number.
+fn is_newer(version: &str, than: &str) -> bool {
+    fn parse(version: &str) -> Vec<u64> {
+        version
+            .split(['.', '-', '+'])
+            .map_while(|part| part.parse().ok())
+            .collect()
+    }
+    parse(version) > parse(than)
+}
+
+/// Opened container. Since all encrypted files are stored uncompressed,
+/// they are read straight from `source`, which allows seeking within them.
+struct Archive<'a, R> {
+    source: &'a mut R,
+    header: Header,
+    /// Start and length of every stored file.
+    files: HashMap<String, (u64, u64)>,
+}
+
+impl<'a, R: Read + Seek> Archive<'a, R> {
+    /// Opens a container, parses its metadata and checks it against `policy`.
+    fn open(source: &'a mut R, policy: DecryptPolicy) -> Result<Self> {
+        let mut zip = ZipArchive::new(source)?;
+
+        let mut raw = Vec::new();
+        by_name(&mut zip, "metadata.json")?.read_to_end(&mut raw)?;
+        let header = Header::parse(raw)?;
+
+        policy.check(&header.metadata.settings)?;
+
+        let mut files = HashMap::new();
+        for i in 0..zip.len() {
+            let file = zip.by_index_raw(i)?;
+            if file.compression() == CompressionMethod::Stored {
+                let span = (file.data_start(), file.compressed_size());
+                files.insert(file.name().to_string(), span);
+            }
+        }
+
+        Ok(Self {
+            source: zip.into_inner(),
+            header,
+            files,
+        })
+    }
+
+    /// Start and length of the file `name`.
+    fn span(&self, name: &str) -> Result<(u64, u64)> {
+        match self.files.get(name) {
+            Some(span) => Ok(*span),
+            None => Err(Error::MissingEntry(name.to_string())),
+        }
+    }
+
+    /// Length of the file `name`.
+    fn len(&self, name: &str) -> Result<u64> {
+        Ok(self.span(name)?.1)
+    }
+
+    /// Settings to decrypt entries with.
+    fn settings(&self) -> CryptSettings {
+        self.header.metadata.settings
+    }
+
+    /// Reads `key.dat`, which holds the data key wrapped by the password.
+    fn wrapped_key(&mut self) -> Result<[u8; WRAPPED_KEY_LEN]> {
+        if self.len("key.dat")? != WRAPPED_KEY_LEN as u64 {
+            return Err(Error::InvalidContainer(
+                "`key.dat` has an invalid length".to_string(),
+            ));
+        }
+        let mut wrapped = [0_u8; WRAPPED_KEY_LEN];
+        self.file("key.dat")?.read_exact(&mut wrapped)?;
+        Ok(wrapped)
+    }
+
+    /// Key the entries are encrypted with: the password itself, or the data
+    /// key unwrapped from `key.dat` with `CryptSettings::wrapped_key`.
+    fn data_key<'k>(
+        &mut self,
+        key: &'k (impl Key + ?Sized),
+        prog: &Progress,
+    ) -> Result<EntryKey<'k>> {
+        let settings = self.settings();
+        if !settings.wrapped_key {
+            return Ok(entry_key(key.secret(), key.pepper()));
+        }
+        let wrapped = self.wrapped_key()?;
+        prog.set_state("Deriving Password".to_string());
+        let key = Peppered::with(key.secret(), key.pepper());
+        let data_key = unwrap_key(key, &wrapped, &settings, prog.clone())?;
+        Ok(entry_key(&data_key[..], None))
+    }
+
+    /// Returns a reader over the file `name`.
+    fn file(&mut self, name: &str) -> Result<Window<&mut R>> {
+        let span = self.span(name)?;
+        Ok(Window::new(&mut *self.source, span)?)
+    }
+
+    /// Like `file` but keeps borrowing `source` after the archive is dropped.
+    fn into_file(self, name: &str) -> Result<Window<&'a mut R>> {
+        let span = self.span(name)?;
+        Ok(Window::new(self.source, span)?)
+    }
+}
+
+/// Reader restricted to `len` bytes of `inner` starting at `start`.
+struct Window<R> {
+    inner: R,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<R: Seek> Window<R> {
+    /// Seeks `inner` to `start`.
+    fn new(mut inner: R, (start, len): (u64, u64)) -> io::Result<Self> {
+        inner.seek(SeekFrom::Start(start))?;
+        Ok(Self {
+            inner,
+            start,
+            len,
+            pos: 0,
+        })
+    }
+}
+
+impl<R: Read> Read for Window<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.len.saturating_sub(self.pos);
+        let max = buf.len().min(remaining.try_into().unwrap_or(usize::MAX));
+        let n = self.inner.read(&mut buf[..max])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Seek> Seek for Window<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        let Some(pos) = pos else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek before start of file",
+            ));
+        };
+        // Seeking past the end is allowed, reading there returns nothing
+        self.inner
+            .seek(SeekFrom::Start(self.start + pos.min(self.len)))?;
+        self.pos = pos;
+        Ok(pos)
+    }
+}
+
+/// Like `ZipArchive::by_name` but reports missing files as `Error::MissingEntry`.
+fn by_name<'a, R: Read + Seek>(zip: &'a mut ZipArchive<R>, name: &str) -> Result<ZipFile<'a>> {
+    match zip.by_name(name) {
+        Ok(file) => Ok(file),
+        Err(ZipError::FileNotFound) => Err(Error::MissingEntry(name.to_string())),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Prefix of all entries in a container holding multiple files.
+const ENTRY_PREFIX: &str = "entries/";
+
+/// Starts a new container by writing the metadata.
+/// Containers are written without seeking, so `dest` may be a pipe or socket.
+fn start_container<W: Write>(dest: W, header: &Header) -> Result<StreamingZipWriter<W>> {
+    let mut zip = StreamingZipWriter::new(dest);
+    zip.set_comment("Created by zeppelin_core");
+
+    zip.start_file("metadata.json")?;
+    zip.write_all(&header.raw)?;
+
+    Ok(zip)
+}
+
+/// Returns the key entries are encrypted with. With `CryptSettings::wrapped_key`
+/// a random data key is drawn from `salts` and stored in `key.dat`, wrapped
+/// by the password. Otherwise this is the password itself.
+fn write_data_key<'k, W: Write>(
+    zip: &mut StreamingZipWriter<W>,
+    key: &'k (impl Key + ?Sized),
+    settings: &CryptSettings,
+    salts: &mut dyn FnMut() -> [u8; 64],
+    prog: &Progress,
+) -> Result<EntryKey<'k>> {
+    if !settings.wrapped_key {
+        return Ok(entry_key(key.secret(), key.pepper()));
+    }
+    let data_key = Zeroizing::new(salts());
+    prog.set_state("Deriving Password".to_string());
+    let key = Peppered::with(key.secret(), key.pepper());
+    let wrapped = wrap_key(key, &data_key, salts(), settings, prog.clone())?;
+
+    zip.start_file("key.dat")?;
+    zip.write_all(&wrapped)?;
+    Ok(entry_key(&data_key[..], None))
+}
+
+/// Key the entries are encrypted with, see `write_data_key` and `Archive::data_key`.
+/// The pepper is only borrowed from the key passed by the caller.
+type EntryKey<'k> = Peppered<'k, Zeroizing<Vec<u8>>>;
+
+fn entry_key<'k>(key: &[u8], pepper: Option<&'k Pepper>) -> EntryKey<'k> {
+    Peppered::with(Zeroizing::new(key.to_vec()), pepper)
+}
+
+/// Encrypts into `{prefix}data.dat` and stores the salt in `{prefix}salt.dat`.
+/// `encrypt` is called with the destination and the associated data and
+/// returns the salt, e.g. by calling `cipher::encrypt`.
+fn write_entry<W: Write>(
+    zip: &mut StreamingZipWriter<W>,
+    prefix: &str,
+    header: &Header,
+    encrypt: impl FnOnce(&mut StreamingZipWriter<W>, &[u8]) -> Result<[u8; 64]>,
+) -> Result<()> {
+    let associated_data = header.associated_data(prefix);
+
+    zip.start_file(format!("{prefix}data.dat"))?;
+    let salt = encrypt(zip, &associated_data)?;
+
+    zip.start_file(format!("{prefix}salt.dat"))?;
+    zip.write_all(&salt)?;
+    Ok(())
+}
+
+/// Decrypts `{prefix}data.dat` using the salt in `{prefix}salt.dat`.
+/// Decrypted data is staged as demanded by `policy.staging`, unless the format
+/// authenticates data before decrypting it. Then the ciphertext is staged.
+fn read_entry_from<R: Read + Seek, W: Write>(
+    archive: &mut Archive<R>,
+    prefix: &str,
+    dest: &mut W,
+    key: impl Key,
+    policy: DecryptPolicy,
+    prog: Progress,
+) -> Result<()> {
+    let settings = archive.settings();
+    let associated_data = archive.header.associated_data(prefix);
+
+    let mut salt = [0_u8; 64];
+    archive
+        .file(&format!("{prefix}salt.dat"))?
+        .read_exact(&mut salt)?;
+
+    let len = archive.len(&format!("{prefix}data.dat"))?;
+    let mut data_file = archive.file(&format!("{prefix}data.dat"))?;
+    let decrypt_from = |mut source: &mut dyn ReadSeek, mut dest: &mut dyn Write| {
+        decrypt_with(
+            &mut source,
+            &mut dest,
+            key,
+            &salt,
+            &associated_data,
+            settings,
+            policy.pipelined,
+            prog,
+        )
+    };
+
+    if settings.format == Format::EncryptThenMac {
+        // Nothing unauthenticated is written, but `data.dat` is read twice
+        return match policy.staging {
+            Staging::Direct => decrypt_from(&mut data_file, dest),
+            Staging::Memory { limit } => {
+                check_staging_limit(len, limit)?;
+                let mut ciphertext = Vec::new();
+                ciphertext.try_reserve_exact(len as usize)?;
+                data_file.read_to_end(&mut ciphertext)?;
+                decrypt_from(&mut io::Cursor::new(ciphertext), dest)
+            }
+            Staging::TempFile => {
+                let mut tmp = tempfile::tempfile()?;
+                io::copy(&mut data_file, &mut tmp)?;
+                decrypt_from(&mut tmp, dest)
+            }
+        };
+    }
+
+    match policy.staging {
+        Staging::Direct => decrypt_from(&mut data_file, dest),
+        Staging::Memory { limit } => {
+            // The plaintext is never larger than `data.dat`, reserving it
+            // upfront ensures there are no copies left behind by reallocation.
+            check_staging_limit(len, limit)?;
+            let mut buffer = Zeroizing::new(Vec::new());
+            buffer.try_reserve_exact(len as usize)?;
+
+            decrypt_from(&mut data_file, &mut *buffer)?;
+            dest.write_all(&buffer)?;
+            Ok(())
+        }
+        Staging::TempFile => {
+            let mut tmp = tempfile::tempfile()?;
+            let res = decrypt_from(&mut data_file, &mut tmp).and_then(|()| {
+                tmp.rewind()?;
+                io::copy(&mut tmp, dest)?;
+                Ok(())
+            });
+
+            let staged = tmp.seek(SeekFrom::End(0))?;
+            tmp.rewind()?;
+            override_writer(&mut tmp, staged)?;
+            res
+        }
+    }
+}
+
+/// Source of `decrypt` that may be staged.
+trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Returns `Error::PolicyViolation` if `len` bytes can not be staged in memory.
+fn check_staging_limit(len: u64, limit: u64) -> Result<()> {
+    if len > limit {
+        return Err(Error::PolicyViolation(PolicyViolation {
+            parameter: "staging",
+            value: len,
+            limit,
+        }));
+    }
+    Ok(())
+}
+
+/// Opens `{prefix}data.dat` for reading, see `DecryptReader`.
+fn open_entry_from<'a, R: Read + Seek>(
+    mut archive: Archive<'a, R>,
+    prefix: &str,
+    key: impl Key,
+    prog: Progress,
+) -> Result<DecryptReader<Window<&'a mut R>>> {
+    let settings = archive.settings();
+    let associated_data = archive.header.associated_data(prefix);
+
+    let mut salt = [0_u8; 64];
+    archive
+        .file(&format!("{prefix}salt.dat"))?
+        .read_exact(&mut salt)?;
+
+    let data_file = archive.into_file(&format!("{prefix}data.dat"))?;
+    DecryptReader::new(data_file, key, &salt, &associated_data, settings, prog)
+}
+
 /// Create a container reading data from `source` and writing to `dest`.
 /// This is the recommended way to encrypt data with this crate.
-pub fn create_container<R: Read + Seek, W: Write + Seek>(
+pub fn create_container<R: Read + Seek, W: Write>(
     source: &mut R,
     dest: &mut W,
-    key: impl AsRef<[u8]>,
+    key: impl Key,
     settings: CryptSettings,
     prog: Option<Progress>,
-) -> ZipResult<()> {
-    let prog = if let Some(inner) = prog {
-        inner
-    } else {
-        Progress::new()
-    };
-    let metadata = ContainerMetadata {
-        version: env!("CARGO_PKG_VERSION").to_string(),
-        settings,
-    };
+) -> Result<()> {
+    create_container_with_salts(source, dest, key, settings, &mut gen_salt, false, prog)
+}
+
+/// Like `create_container` but computes the keystream in a separate thread,
+/// see `cipher::encrypt_pipelined`. The container is the same.
+pub fn create_container_pipelined<R: Read + Seek, W: Write>(
+    source: &mut R,
+    dest: &mut W,
+    key: impl Key,
+    settings: CryptSettings,
+    prog: Option<Progress>,
+) -> Result<()> {
+    create_container_with_salts(source, dest, key, settings, &mut gen_salt, true, prog)
+}
+
+/// `create_container` taking its salts from `salts` instead of the OS.
+/// Only used to create reproducible test vectors.
+pub(crate) fn create_container_with_salts<R: Read + Seek, W: Write>(
+    source: &mut R,
+    dest: &mut W,
+    key: impl Key,
+    settings: CryptSettings,
+    salts: &mut dyn FnMut() -> [u8; 64],
+    pipelined: bool,
+    prog: Option<Progress>,
+) -> Result<()> {
+    let prog = prog.unwrap_or_default();
     let len = source.seek(SeekFrom::End(0))?;
     source.rewind()?;
 
     prog.set_max_data(len as usize);
 
-    let file_options = FileOptions::default()
-        .last_modified_time(DateTime::from_date_and_time(1980, 1, 1, 0, 0, 0).unwrap())
-        .compression_method(CompressionMethod::Stored);
+    let header = Header::new(settings);
+    let mut zip = start_container(dest, &header)?;
+    let key = write_data_key(&mut zip, &key, &settings, salts, &prog)?;
+    write_entry(&mut zip, "", &header, |zip, associated_data| {
+        encrypt_with_salts(
+            source,
+            zip,
+            &key,
+            associated_data,
+            settings,
+            salts,
+            pipelined,
+            prog,
+        )
+    })?;
+
+    zip.finish()?;
+    Ok(())
+}
 
-    let mut zip = ZipWriter::new(dest);
-    zip.set_comment("Created by zeppelin_core");
+/// Like `create_container` but reads `source` only once and never seeks,
+/// so that it can be a pipe, stdin or a socket. Since its length is unknown,
+/// `Progress::get_bytes` reports how much has been encrypted.
+/// Returns `Error::InvalidSettings` for `Format::Legacy` and `Format::AuthenticatedHeader`.
+pub fn create_container_from_reader<R: Read, W: Write>(
+    source: &mut R,
+    dest: &mut W,
+    key: impl Key,
+    settings: CryptSettings,
+    prog: Option<Progress>,
+) -> Result<()> {
+    check_single_pass(&settings)?;
+    let prog = prog.unwrap_or_default();
+    prog.set_length_unknown();
 
-    zip.start_file(
-        "metadata.json",
-        file_options.compression_method(CompressionMethod::Deflated),
-    )?;
-    
-    zip.write_all(serde_json::to_string(&metadata).unwrap().as_bytes())?;
+    let header = Header::new(settings);
+    let mut zip = start_container(dest, &header)?;
+    let key = write_data_key(&mut zip, &key, &settings, &mut gen_salt, &prog)?;
+    write_entry(&mut zip, "", &header, |zip, associated_data| {
+        encrypt_single_pass(source, zip, &key, associated_data, settings, prog)
+    })?;
 
-    zip.start_file("data.dat", file_options)?;
-    let salt = encrypt(source, &mut zip, key, settings, prog)?;
+    zip.finish()?;
+    Ok(())
+}
 
-    zip.start_file("salt.dat", file_options)?;
-    zip.write_all(&salt)?;
+/// Create a container holding every file below the directory `dir`.
+/// Every file is encrypted separately with its own salt and MAC.
+/// Entries are named by their path relative to `dir` using `/` as separator.
+/// ### Note:
+/// Symbolic links and empty directories are not stored.
+pub fn create_container_from_dir<W: Write>(
+    dir: impl AsRef<Path>,
+    dest: &mut W,
+    key: impl Key,
+    settings: CryptSettings,
+    prog: Option<Progress>,
+) -> Result<()> {
+    let prog = prog.unwrap_or_default();
+
+    let mut files = Vec::new();
+    collect_files(dir.as_ref(), dir.as_ref(), &mut files)?;
+
+    let mut len = 0;
+    for (path, _) in &files {
+        len += fs::metadata(path)?.len();
+    }
+    prog.set_max_data(len as usize);
+
+    let header = Header::new(settings);
+    let mut zip = start_container(dest, &header)?;
+    let key = write_data_key(&mut zip, &key, &settings, &mut gen_salt, &prog)?;
+    for (path, name) in files {
+        let mut source = fs::File::open(path)?;
+        let prefix = format!("{ENTRY_PREFIX}{name}/");
+        write_entry(&mut zip, &prefix, &header, |zip, associated_data| {
+            encrypt_with_salts(
+                &mut source,
+                zip,
+                &key,
+                associated_data,
+                settings,
+                &mut gen_salt,
+                false,
+                prog.clone(),
+            )
+        })?;
+    }
 
     zip.finish()?;
     Ok(())
 }
 
+/// Recursively collects all files below `dir` together with their entry name.
+fn collect_files(root: &Path, dir: &Path, files: &mut Vec<(PathBuf, String)>) -> io::Result<()> {
+    let mut children = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    children.sort_by_key(|child| child.file_name());
+
+    for child in children {
+        let file_type = child.file_type()?;
+        if file_type.is_dir() {
+            collect_files(root, &child.path(), files)?;
+        } else if file_type.is_file() {
+            let path = child.path();
+            let name = entry_name(path.strip_prefix(root).unwrap())?;
+            files.push((path, name));
+        }
+    }
+    Ok(())
+}
+
+/// Converts a relative path into an entry name.
+fn entry_name(path: &Path) -> io::Result<String> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component.as_os_str().to_str() {
+            Some(part) => parts.push(part),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Path {path:?} is not valid UTF-8"),
+                ))
+            }
+        }
+    }
+    Ok(parts.join("/"))
+}
+
+/// Converts an entry name into a path below `dir`, rejecting names that
+/// would escape it.
+fn entry_path(dir: &Path, name: &str) -> Result<PathBuf> {
+    let mut path = dir.to_path_buf();
+    for part in name.split('/') {
+        let mut components = Path::new(part).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(part)), None) => path.push(part),
+            _ => return Err(Error::InvalidEntryName(name.to_string())),
+        }
+    }
+    Ok(path)
+}
+
 /// Decrypt a container reading from `source` and writing to `dest`.
-/// Returns `true` if container was decrypted successfully, e.g. the
-/// same password was used for encryption and decryption. Returns
-/// `ZipError` if container is invalid.
+/// Returns `Error::WrongPassword` if a different password was used for
+/// encryption, `Error::Corrupted` if the data and `Error::HeaderTampered`
+/// if `metadata.json` has been modified.
+/// If the settings of the container exceed `policy` `Error::PolicyViolation`
+/// is returned before any memory is allocated for decryption.
+/// If the container requires a pepper, pass the key as `secret::Peppered`.
 /// ### Note:
-/// Even if the password does not match, data will be written to `dest`
-/// to avoid caching.
+/// Containers using `Format::EncryptThenMac` are authenticated before anything
+/// is written to `dest`. Since `data.dat` is then read twice, `policy.staging`
+/// should not be `Staging::Direct` if `source` may be modified concurrently.
+/// For older formats data will be written to `dest` even
+/// if the MAC does not match, to avoid caching, unless `policy.staging` demands
+/// otherwise. Only for `Format::Legacy` this includes the case of a wrong password.
+/// `Format::SinglePass` reads `data.dat` only once and authenticates it while
+/// decrypting, so `policy.staging` applies as well. So does it for
+/// `Format::Chunked`, which returns `Error::CorruptedChunk` or `Error::Truncated`.
 pub fn read_container<R: Read + Seek, W: Write>(
     source: &mut R,
     dest: &mut W,
-    key: impl AsRef<[u8]>,
+    key: impl Key,
+    policy: DecryptPolicy,
     prog: Option<Progress>,
-) -> ZipResult<bool> {
-    let prog = if let Some(inner) = prog {
-        inner
-    } else {
-        Progress::new()
-    };
+) -> Result<()> {
+    let prog = prog.unwrap_or_default();
+
+    let mut archive = Archive::open(source, policy)?;
 
+    prog.set_max_data(archive.len("data.dat")? as usize);
+    let key = archive.data_key(&key, &prog)?;
+    read_entry_from(&mut archive, "", dest, &key, policy, prog)
+}
+
+/// Reads the metadata of a container without decrypting it.
+/// ### Note:
+/// The metadata is only authenticated once an entry is decrypted.
+pub fn read_metadata<R: Read + Seek>(source: &mut R) -> Result<ContainerMetadata> {
     let mut zip = ZipArchive::new(source)?;
 
-    let mut metadata_file = zip.by_name("metadata.json")?;
-    let metadata: serde_json::Result<ContainerMetadata> =
-        serde_json::from_reader(&mut metadata_file);
-    let metadata = if let Ok(inner) = metadata {
-        inner
-    } else {
-        // This seems a little long
-        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid `metadata.json` found").into());
+    let mut raw = Vec::new();
+    by_name(&mut zip, "metadata.json")?.read_to_end(&mut raw)?;
+    Ok(Header::parse(raw)?.metadata)
+}
+
+/// Lists the names of all entries of a container created by `create_container_from_dir`.
+/// Containers created by `create_container` have no named entries.
+pub fn list_entries<R: Read + Seek>(source: &mut R) -> Result<Vec<String>> {
+    let zip = ZipArchive::new(source)?;
+    let mut entries: Vec<String> = zip
+        .file_names()
+        .filter_map(|name| name.strip_prefix(ENTRY_PREFIX)?.strip_suffix("/data.dat"))
+        .map(|name| name.to_string())
+        .collect();
+    entries.sort();
+    Ok(entries)
+}
+
+/// Decrypt the single entry `name` of a container, writing it to `dest`.
+/// Returns `Error::MissingEntry` if there is no such entry.
+pub fn read_entry<R: Read + Seek, W: Write>(
+    source: &mut R,
+    name: &str,
+    dest: &mut W,
+    key: impl Key,
+    policy: DecryptPolicy,
+    prog: Option<Progress>,
+) -> Result<()> {
+    let prog = prog.unwrap_or_default();
+
+    let mut archive = Archive::open(source, policy)?;
+
+    let prefix = format!("{ENTRY_PREFIX}{name}/");
+    prog.set_max_data(archive.len(&format!("{prefix}data.dat"))? as usize);
+    let key = archive.data_key(&key, &prog)?;
+    read_entry_from(&mut archive, &prefix, dest, &key, policy, prog)
+}
+
+/// Opens the data of a container created by `create_container` as a reader
+/// that decrypts only what is read and supports seeking, see `DecryptReader`.
+/// The data is authenticated before this returns.
+/// ### Note:
+/// Containers using `Format::Legacy` or `Format::AuthenticatedHeader` can not
+/// be opened. Seeking backwards additionally requires `KeystreamMode::Counter`.
+/// With `Format::Chunked` only the chunks that are read are authenticated.
+/// Otherwise `source` must not be modified while the reader is in use,
+/// see `DecryptReader::new`.
+pub fn open_container<'a, R: Read + Seek>(
+    source: &'a mut R,
+    key: impl Key,
+    policy: DecryptPolicy,
+    prog: Option<Progress>,
+) -> Result<DecryptReader<impl Read + Seek + 'a>> {
+    let prog = prog.unwrap_or_default();
+    let mut archive = Archive::open(source, policy)?;
+    let key = archive.data_key(&key, &prog)?;
+    open_entry_from(archive, "", &key, prog)
+}
+
+/// Like `open_container` but opens the single entry `name`.
+/// Returns `Error::MissingEntry` if there is no such entry.
+pub fn open_entry<'a, R: Read + Seek>(
+    source: &'a mut R,
+    name: &str,
+    key: impl Key,
+    policy: DecryptPolicy,
+    prog: Option<Progress>,
+) -> Result<DecryptReader<impl Read + Seek + 'a>> {
+    let prog = prog.unwrap_or_default();
+    let mut archive = Archive::open(source, policy)?;
+    let key = archive.data_key(&key, &prog)?;
+    let prefix = format!("{ENTRY_PREFIX}{name}/");
+    open_entry_from(archive, &prefix, &key, prog)
+}
+
+/// Decrypt entries of a container into the directory `dir`, recreating
+/// the directory structure. If `names` is `None` all entries are extracted.
+/// Stops at the first entry that fails to decrypt.
+/// ### Note:
+/// Like `read_container`, entries are written even if their MAC does not match,
+/// unless `policy.staging` demands otherwise.
+pub fn extract_container<R: Read + Seek>(
+    source: &mut R,
+    dir: impl AsRef<Path>,
+    key: impl Key,
+    names: Option<&[&str]>,
+    policy: DecryptPolicy,
+    prog: Option<Progress>,
+) -> Result<()> {
+    let prog = prog.unwrap_or_default();
+
+    let names: Vec<String> = match names {
+        Some(names) => names.iter().map(|name| name.to_string()).collect(),
+        None => list_entries(source)?,
     };
-    drop(metadata_file);
+    source.rewind()?;
 
-    let mut salt = [0_u8; 64];
-    let mut salt_file = zip.by_name("salt.dat")?;
-    salt_file.read_exact(&mut salt)?;
-    drop(salt_file);
+    let mut archive = Archive::open(source, policy)?;
+
+    // Validate all names before writing anything
+    let mut len = 0;
+    let mut paths = Vec::new();
+    for name in &names {
+        len += archive.len(&format!("{ENTRY_PREFIX}{name}/data.dat"))?;
+        paths.push(entry_path(dir.as_ref(), name)?);
+    }
+    prog.set_max_data(len as usize);
+
+    // With a wrapped key the password is only derived once for all entries
+    let key = archive.data_key(&key, &prog)?;
+    for (name, path) in names.iter().zip(paths) {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut dest = fs::File::create(&path)?;
+        let prefix = format!("{ENTRY_PREFIX}{name}/");
+        read_entry_from(&mut archive, &prefix, &mut dest, &key, policy, prog.clone())?;
+    }
+
+    Ok(())
+}
+
+/// Changes the password of a container created with `CryptSettings::wrapped_key`
+/// from `old` to `new`. Only `key.dat`, which holds the data key wrapped by the
+/// password, is overwritten in place, so this is fast regardless of the size of
+/// the container. If the container requires a pepper, both `old` and `new`
+/// have to carry it, see `secret::Peppered`.
+/// Returns `Error::WrongPassword` if `old` does not match and
+/// `Error::InvalidSettings` if the container has no wrapped key.
+/// ### Note:
+/// Copies of the container made before still open with the old password.
+pub fn change_password<F: Read + Write + Seek>(
+    container: &mut F,
+    old: impl Key,
+    new: impl Key,
+    policy: DecryptPolicy,
+    prog: Option<Progress>,
+) -> Result<()> {
+    let prog = prog.unwrap_or_default();
 
-    let mut data_file = zip.by_name("data.dat")?;
-    decrypt_salt(&mut salt, &mut data_file)?;
-    drop(data_file);
+    let mut archive = Archive::open(container, policy)?;
+    let settings = archive.settings();
+    if !settings.wrapped_key {
+        return Err(Error::InvalidSettings(
+            "the container has no wrapped key, see `CryptSettings::wrapped_key`".to_string(),
+        ));
+    }
+    let wrapped = archive.wrapped_key()?;
+    let (data_start, _) = archive.span("key.dat")?;
 
-    let mut data_file = zip.by_name("data.dat")?;
-    prog.set_max_data(data_file.size() as usize);
-    let success = decrypt(&mut data_file, dest, key, &salt, metadata.settings, prog)?;
+    prog.set_state("Deriving Password".to_string());
+    let data_key = unwrap_key(old, &wrapped, &settings, prog.clone())?;
+    let wrapped = wrap_key(new, &data_key, gen_salt(), &settings, prog)?;
 
-    Ok(success)
+    let container = archive.source;
+    let central_header_start =
+        by_name(&mut ZipArchive::new(&mut *container)?, "key.dat")?.central_header_start();
+    overwrite_file(container, data_start, central_header_start, &wrapped)?;
+    Ok(())
 }
 
 /// Used only internally; Writes random bytes to writer
@@ -122,7 +1005,7 @@ fn override_writer<W: Write>(dest: &mut W, len: u64) -> io::Result<()> {
 }
 
 /// Completely overrides file with random noise, then deletes it.
-pub fn erase_file(file: PathBuf) -> std::io::Result<()> {
+pub fn erase_file(file: PathBuf) -> Result<()> {
     {
         let len: u64 = fs::metadata(file.clone())?.len();
         let mut tmp = fs::OpenOptions::new().write(true).open(file.clone())?;
@@ -138,6 +1021,8 @@ pub fn erase_file(file: PathBuf) -> std::io::Result<()> {
 mod tests {
     use std::io;
 
+    use zip::{write::FileOptions, ZipWriter};
+
     use crate::cipher;
 
     use super::*;
@@ -180,9 +1065,15 @@ mod tests {
 
         let mut res = io::Cursor::new(Vec::<u8>::new());
 
-        let success = read_container(&mut container, &mut res, "passwd", None).unwrap();
+        read_container(
+            &mut container,
+            &mut res,
+            "passwd",
+            DecryptPolicy::default(),
+            None,
+        )
+        .unwrap();
 
-        assert!(success);
         assert_eq!(source.into_inner(), res.into_inner());
     }
 
@@ -205,8 +1096,1127 @@ mod tests {
 
         let mut res = io::Cursor::new(Vec::<u8>::new());
 
-        let success = read_container(&mut container, &mut res, "wrong passwd", None).unwrap();
+        let err = read_container(
+            &mut container,
+            &mut res,
+            "wrong passwd",
+            DecryptPolicy::default(),
+            None,
+        )
+        .unwrap_err();
+
+        assert!(matches!(err, Error::WrongPassword));
+        assert!(res.into_inner().is_empty());
+    }
 
-        assert!(!success);
+    /// Creates an empty directory below the OS temp directory.
+    fn temp_dir() -> PathBuf {
+        let mut rng = ChaCha20Rng::from_entropy();
+        let dir = std::env::temp_dir().join(format!("zeppelin_{:016x}", rng.gen::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn container_dir_read_and_write() {
+        let src = temp_dir();
+        fs::create_dir_all(src.join("a/b")).unwrap();
+        fs::write(src.join("top.txt"), b"top level").unwrap();
+        fs::write(src.join("a/b/nested.bin"), (0..=255).collect::<Vec<u8>>()).unwrap();
+        fs::write(src.join("a/empty"), b"").unwrap();
+
+        let mut container = io::Cursor::new(Vec::<u8>::new());
+        create_container_from_dir(
+            &src,
+            &mut container,
+            "passwd",
+            cipher::CryptSettings::default_for_testing(),
+            None,
+        )
+        .unwrap();
+        container.rewind().unwrap();
+
+        let entries = list_entries(&mut container).unwrap();
+        assert_eq!(entries, ["a/b/nested.bin", "a/empty", "top.txt"]);
+        container.rewind().unwrap();
+
+        let dest = temp_dir();
+        extract_container(
+            &mut container,
+            &dest,
+            "passwd",
+            None,
+            DecryptPolicy::default(),
+            None,
+        )
+        .unwrap();
+        for name in entries {
+            assert_eq!(
+                fs::read(src.join(&name)).unwrap(),
+                fs::read(dest.join(&name)).unwrap()
+            );
+        }
+
+        fs::remove_dir_all(src).unwrap();
+        fs::remove_dir_all(dest).unwrap();
+    }
+
+    #[test]
+    fn container_read_single_entry() {
+        let src = temp_dir();
+        fs::write(src.join("one"), b"first").unwrap();
+        fs::write(src.join("two"), b"second").unwrap();
+
+        let mut container = io::Cursor::new(Vec::<u8>::new());
+        create_container_from_dir(
+            &src,
+            &mut container,
+            "passwd",
+            cipher::CryptSettings::default_for_testing(),
+            None,
+        )
+        .unwrap();
+        fs::remove_dir_all(src).unwrap();
+
+        container.rewind().unwrap();
+        let mut res = Vec::new();
+        read_entry(
+            &mut container,
+            "two",
+            &mut res,
+            "passwd",
+            DecryptPolicy::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(res, b"second");
+
+        container.rewind().unwrap();
+        let dest = temp_dir();
+        extract_container(
+            &mut container,
+            &dest,
+            "passwd",
+            Some(&["one"]),
+            DecryptPolicy::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(fs::read(dest.join("one")).unwrap(), b"first");
+        assert!(!dest.join("two").exists());
+        fs::remove_dir_all(dest).unwrap();
+    }
+
+    #[test]
+    fn container_from_reader() {
+        let data: Vec<u8> = (0..10_000_u32).map(|b| (b % 7) as u8).collect();
+        let prog = Progress::new();
+
+        let mut container = io::Cursor::new(Vec::<u8>::new());
+        create_container_from_reader(
+            &mut &data[..],
+            &mut container,
+            "passwd",
+            cipher::CryptSettings::default_for_testing(),
+            Some(prog.clone()),
+        )
+        .unwrap();
+        assert!(!prog.is_length_known());
+        assert_eq!(prog.get_bytes(), data.len() as u64);
+
+        container.rewind().unwrap();
+        let mut res = Vec::new();
+        read_container(
+            &mut container,
+            &mut res,
+            "passwd",
+            DecryptPolicy::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(res, data);
+
+        let settings = cipher::CryptSettings {
+            format: Format::Legacy,
+            ..cipher::CryptSettings::default_for_testing()
+        };
+        let mut container = io::Cursor::new(Vec::<u8>::new());
+        assert!(matches!(
+            create_container_from_reader(&mut &data[..], &mut container, "passwd", settings, None),
+            Err(Error::InvalidSettings(_))
+        ));
+        assert!(container.get_ref().is_empty());
+    }
+
+    #[test]
+    fn container_to_pipe() {
+        let data: Vec<u8> = (0..10_000_u32).map(|b| (b % 11) as u8).collect();
+
+        // `Vec<u8>` implements `Write` but not `Seek`
+        let mut container = Vec::new();
+        create_container_from_reader(
+            &mut &data[..],
+            &mut container,
+            "passwd",
+            cipher::CryptSettings::default_for_testing(),
+            None,
+        )
+        .unwrap();
+
+        let mut res = Vec::new();
+        read_container(
+            &mut io::Cursor::new(container),
+            &mut res,
+            "passwd",
+            DecryptPolicy::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(res, data);
+
+        let src = temp_dir();
+        fs::write(src.join("one"), b"first").unwrap();
+        fs::write(src.join("two"), &data).unwrap();
+        let mut container = Vec::new();
+        create_container_from_dir(
+            &src,
+            &mut container,
+            "passwd",
+            cipher::CryptSettings::default_for_testing(),
+            None,
+        )
+        .unwrap();
+        fs::remove_dir_all(src).unwrap();
+
+        let mut container = io::Cursor::new(container);
+        assert_eq!(list_entries(&mut container).unwrap(), ["one", "two"]);
+        let mut res = Vec::new();
+        read_entry(
+            &mut container,
+            "one",
+            &mut res,
+            "passwd",
+            DecryptPolicy::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(res, b"first");
+    }
+
+    #[test]
+    fn open_container_and_entry() {
+        let settings = cipher::CryptSettings {
+            mode: cipher::KeystreamMode::Counter,
+            ..cipher::CryptSettings::default_for_testing()
+        };
+        let data: Vec<u8> = (0..5000_u32).map(|b| (b % 251) as u8).collect();
+
+        let mut container = io::Cursor::new(Vec::<u8>::new());
+        create_container(
+            &mut io::Cursor::new(data.clone()),
+            &mut container,
+            "passwd",
+            settings,
+            None,
+        )
+        .unwrap();
+        container.rewind().unwrap();
+        let mut reader =
+            open_container(&mut container, "passwd", DecryptPolicy::default(), None).unwrap();
+        assert_eq!(reader.len(), 5000);
+        reader.seek(SeekFrom::Start(4000)).unwrap();
+        let mut res = vec![0_u8; 100];
+        reader.read_exact(&mut res).unwrap();
+        assert_eq!(res, data[4000..4100]);
+        reader.seek(SeekFrom::Start(10)).unwrap();
+        reader.read_exact(&mut res).unwrap();
+        assert_eq!(res, data[10..110]);
+
+        let src = temp_dir();
+        fs::write(src.join("one"), b"first").unwrap();
+        fs::write(src.join("two"), &data).unwrap();
+        let mut container = io::Cursor::new(Vec::<u8>::new());
+        create_container_from_dir(&src, &mut container, "passwd", settings, None).unwrap();
+        fs::remove_dir_all(src).unwrap();
+
+        container.rewind().unwrap();
+        let mut reader = open_entry(
+            &mut container,
+            "two",
+            "passwd",
+            DecryptPolicy::default(),
+            None,
+        )
+        .unwrap();
+        reader.seek(SeekFrom::End(-100)).unwrap();
+        reader.read_exact(&mut res).unwrap();
+        assert_eq!(res, data[4900..]);
+        drop(reader);
+
+        container.rewind().unwrap();
+        assert!(matches!(
+            open_entry(
+                &mut container,
+                "two",
+                "wrong",
+                DecryptPolicy::default(),
+                None
+            ),
+            Err(Error::WrongPassword)
+        ));
+    }
+
+    #[test]
+    fn entry_path_stays_inside() {
+        let dir = Path::new("out");
+        assert_eq!(entry_path(dir, "a/b").unwrap(), dir.join("a").join("b"));
+        assert!(entry_path(dir, "../a").is_err());
+        assert!(entry_path(dir, "a//b").is_err());
+        assert!(entry_path(dir, "/a").is_err());
+        assert!(entry_path(dir, "a/./b").is_err());
+    }
+
+    fn file_options() -> FileOptions {
+        FileOptions::default().compression_method(CompressionMethod::Stored)
+    }
+
+    /// Returns a copy of `container` with `metadata.json` replaced by `metadata`.
+    fn replace_metadata(
+        container: &mut io::Cursor<Vec<u8>>,
+        metadata: &[u8],
+    ) -> io::Cursor<Vec<u8>> {
+        let mut zip = ZipArchive::new(container).unwrap();
+        let mut res = ZipWriter::new(io::Cursor::new(Vec::new()));
+        for i in 0..zip.len() {
+            let file = zip.by_index_raw(i).unwrap();
+            if file.name() == "metadata.json" {
+                res.start_file("metadata.json", file_options()).unwrap();
+                res.write_all(metadata).unwrap();
+            } else {
+                res.raw_copy_file(file).unwrap();
+            }
+        }
+        let mut res = res.finish().unwrap();
+        res.rewind().unwrap();
+        res
+    }
+
+    #[test]
+    fn container_tampered_metadata() {
+        let mut source = io::Cursor::new(b"data".to_vec());
+        let settings = cipher::CryptSettings::default_for_testing();
+
+        let mut container = io::Cursor::new(Vec::<u8>::new());
+        create_container(&mut source, &mut container, "passwd", settings, None).unwrap();
+        container.rewind().unwrap();
+
+        let tampered = ContainerMetadata {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            settings: cipher::CryptSettings {
+                step_delta: settings.step_delta + 1,
+                ..settings
+            },
+        };
+        let tampered = serde_json::to_vec(&tampered).unwrap();
+        let mut container = replace_metadata(&mut container, &tampered);
+
+        let mut res = io::Cursor::new(Vec::<u8>::new());
+        let err = read_container(
+            &mut container,
+            &mut res,
+            "passwd",
+            DecryptPolicy::default(),
+            None,
+        )
+        .unwrap_err();
+
+        assert!(matches!(err, Error::HeaderTampered));
+        assert!(res.into_inner().is_empty());
+    }
+
+    #[test]
+    fn metadata_without_format_is_legacy() {
+        let raw = br#"{"version":"0.1.1","settings":{"s_cost":1000,"t_cost":2,"step_delta":3}}"#;
+        let header = Header::parse(raw.to_vec()).unwrap();
+        assert_eq!(header.metadata.settings.format, cipher::Format::Legacy);
+        assert_eq!(header.metadata.settings.kdf, KdfSettings::default());
+        assert_eq!(header.metadata.settings.revision, crate::hash::Revision::V0);
+        assert_eq!(
+            header.metadata.settings.indexing,
+            crate::hash::Indexing::Independent
+        );
+        assert_eq!(header.metadata.settings.p_cost, 1);
+        assert_eq!(
+            header.metadata.settings.hash,
+            crate::hash::HashFunction::Sha3_512
+        );
+    }
+
+    #[test]
+    fn container_exceeding_policy() {
+        let mut source = io::Cursor::new(b"data".to_vec());
+        let settings = cipher::CryptSettings::default_for_testing();
+
+        let mut container = io::Cursor::new(Vec::<u8>::new());
+        create_container(&mut source, &mut container, "passwd", settings, None).unwrap();
+        container.rewind().unwrap();
+
+        let policy = DecryptPolicy {
+            max_memory: settings.s_cost * 64 - 1,
+            ..DecryptPolicy::default()
+        };
+        let mut res = io::Cursor::new(Vec::<u8>::new());
+        let err = read_container(&mut container, &mut res, "passwd", policy, None).unwrap_err();
+
+        assert!(matches!(
+            err,
+            Error::PolicyViolation(PolicyViolation {
+                parameter: "memory",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn crafted_zero_costs_are_errors() {
+        let mut container = io::Cursor::new(Vec::<u8>::new());
+        create_container(
+            &mut io::Cursor::new(b"data".to_vec()),
+            &mut container,
+            "passwd",
+            cipher::CryptSettings::default_for_testing(),
+            None,
+        )
+        .unwrap();
+
+        // Metadata without `format` is read as `Format::Legacy`
+        for settings in [
+            r#"{"s_cost":0,"t_cost":2,"step_delta":3}"#,
+            r#"{"s_cost":1000,"t_cost":0,"step_delta":3}"#,
+            r#"{"s_cost":1000,"t_cost":2,"step_delta":0}"#,
+        ] {
+            let metadata = format!(r#"{{"version":"0.1.1","settings":{settings}}}"#);
+            let mut crafted = replace_metadata(&mut container, metadata.as_bytes());
+            for policy in [DecryptPolicy::default(), DecryptPolicy::unlimited()] {
+                let res = read_container(&mut crafted, &mut Vec::new(), "passwd", policy, None);
+                assert!(matches!(res, Err(Error::InvalidSettings(_))), "{settings}");
+            }
+        }
+    }
+
+    #[test]
+    fn policy_rejects_huge_settings() {
+        let settings = cipher::CryptSettings {
+            s_cost: u64::MAX,
+            ..cipher::CryptSettings::default_for_testing()
+        };
+        let err = DecryptPolicy::default().check(&settings).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::PolicyViolation(PolicyViolation {
+                value: u64::MAX,
+                ..
+            })
+        ));
+        assert!(DecryptPolicy::unlimited().check(&settings).is_ok());
+
+        // Lanes are bounded on their own, since they fit into little memory
+        let settings = cipher::CryptSettings {
+            s_cost: 1 << 22,
+            p_cost: 1 << 20,
+            ..cipher::CryptSettings::default_for_testing()
+        };
+        let err = DecryptPolicy::default().check(&settings).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::PolicyViolation(PolicyViolation {
+                parameter: "p_cost",
+                ..
+            })
+        ));
+
+        let settings = cipher::CryptSettings {
+            kdf: KdfSettings::Scrypt(crate::kdf::ScryptSettings {
+                log_n: 40,
+                r: 8,
+                p: 1,
+            }),
+            ..cipher::CryptSettings::default_for_testing()
+        };
+        let err = DecryptPolicy::default().check(&settings).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::PolicyViolation(PolicyViolation {
+                parameter: "kdf_memory",
+                ..
+            })
+        ));
+
+        let settings = cipher::CryptSettings {
+            kdf: KdfSettings::Scrypt(crate::kdf::ScryptSettings {
+                log_n: 10,
+                r: 8,
+                p: 1 << 20,
+            }),
+            ..cipher::CryptSettings::default_for_testing()
+        };
+        let err = DecryptPolicy::default().check(&settings).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::PolicyViolation(PolicyViolation {
+                parameter: "kdf_p",
+                ..
+            })
+        ));
+
+        let settings = cipher::CryptSettings {
+            kdf: KdfSettings::Balloon(crate::kdf::BalloonSettings {
+                step_delta: 1 << 20,
+                ..Default::default()
+            }),
+            ..cipher::CryptSettings::default_for_testing()
+        };
+        let err = DecryptPolicy::default().check(&settings).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::PolicyViolation(PolicyViolation {
+                parameter: "kdf_step_delta",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn container_missing_salt() {
+        let mut source = io::Cursor::new(b"data".to_vec());
+        let settings = cipher::CryptSettings::default_for_testing();
+
+        let mut container = io::Cursor::new(Vec::<u8>::new());
+        create_container(&mut source, &mut container, "passwd", settings, None).unwrap();
+        container.rewind().unwrap();
+
+        let mut zip = ZipArchive::new(&mut container).unwrap();
+        let mut res = ZipWriter::new(io::Cursor::new(Vec::new()));
+        for i in 0..zip.len() {
+            let file = zip.by_index_raw(i).unwrap();
+            if file.name() != "salt.dat" {
+                res.raw_copy_file(file).unwrap();
+            }
+        }
+        let mut container = res.finish().unwrap();
+        container.rewind().unwrap();
+
+        let err = read_container(
+            &mut container,
+            &mut io::sink(),
+            "passwd",
+            DecryptPolicy::default(),
+            None,
+        )
+        .unwrap_err();
+        assert!(matches!(err, Error::MissingEntry(name) if name == "salt.dat"));
+    }
+
+    #[test]
+    fn metadata_from_newer_version() {
+        let raw = br#"{"version":"999.0.0","settings":{"s_cost":1000,"t_cost":2,"step_delta":3,"format":"FromTheFuture"}}"#;
+        let err = Header::parse(raw.to_vec()).err().unwrap();
+        assert!(matches!(err, Error::UnsupportedVersion(version) if version == "999.0.0"));
+
+        let raw = br#"{"version":"0.1.0","settings":{}}"#;
+        let err = Header::parse(raw.to_vec()).err().unwrap();
+        assert!(matches!(err, Error::InvalidContainer(_)));
+    }
+
+    #[test]
+    fn staging_withholds_unauthenticated_data() {
+        let data: Vec<u8> = (0..10_u64.pow(4)).map(|b| b as u8).collect();
+        let settings = cipher::CryptSettings {
+            format: cipher::Format::Legacy,
+            ..cipher::CryptSettings::default_for_testing()
+        };
+
+        let mut container = io::Cursor::new(Vec::<u8>::new());
+        create_container(
+            &mut io::Cursor::new(data.clone()),
+            &mut container,
+            "passwd",
+            settings,
+            None,
+        )
+        .unwrap();
+
+        let read = |key: &str, staging: Staging| {
+            let policy = DecryptPolicy {
+                staging,
+                ..DecryptPolicy::default()
+            };
+            let mut res = Vec::new();
+            let mut container = io::Cursor::new(container.get_ref());
+            let success = read_container(&mut container, &mut res, key, policy, None);
+            (success.is_ok(), res)
+        };
+
+        let stagings = [Staging::Memory { limit: 1 << 20 }, Staging::TempFile];
+        for staging in stagings {
+            assert_eq!(read("passwd", staging), (true, data.clone()));
+            assert_eq!(read("wrong passwd", staging), (false, Vec::new()));
+        }
+        // Legacy containers can't detect a wrong password early
+        assert_eq!(read("wrong passwd", Staging::Direct).1.len(), data.len());
+
+        let (success, res) = read("passwd", Staging::Memory { limit: 100 });
+        assert!(!success);
+        assert!(res.is_empty());
+    }
+
+    /// Source that turns into `tampered` once the byte at `trigger` has been read.
+    struct Swapping {
+        source: io::Cursor<Vec<u8>>,
+        tampered: Vec<u8>,
+        trigger: u64,
+    }
+
+    impl Read for Swapping {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let pos = self.source.position();
+            let n = self.source.read(buf)?;
+            if (pos..pos + n as u64).contains(&self.trigger) && !self.tampered.is_empty() {
+                *self.source.get_mut() = std::mem::take(&mut self.tampered);
+            }
+            Ok(n)
+        }
+    }
+
+    impl Seek for Swapping {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.source.seek(pos)
+        }
+    }
+
+    #[test]
+    fn staging_keeps_ciphertext_from_changing() {
+        let data: Vec<u8> = (0..10_000_u32).map(|b| (b % 11) as u8).collect();
+        let settings = cipher::CryptSettings::default_for_testing();
+        assert_eq!(settings.format, Format::EncryptThenMac);
+
+        let mut container = Vec::new();
+        create_container(
+            &mut io::Cursor::new(data.clone()),
+            &mut io::Cursor::new(&mut container),
+            "passwd",
+            settings,
+            None,
+        )
+        .unwrap();
+
+        // Swap in modified data once the MAC of `data.dat` has been read
+        let mut zip = ZipArchive::new(io::Cursor::new(&container)).unwrap();
+        let file = zip.by_name("data.dat").unwrap();
+        let (start, len) = (file.data_start(), file.compressed_size());
+        drop(file);
+        let mut tampered = container.clone();
+        tampered[start as usize + 1000] ^= 1;
+
+        let read = |staging: Staging| {
+            let mut source = Swapping {
+                source: io::Cursor::new(container.clone()),
+                tampered: tampered.clone(),
+                trigger: start + len - 1,
+            };
+            let policy = DecryptPolicy {
+                staging,
+                ..DecryptPolicy::default()
+            };
+            let mut res = Vec::new();
+            read_container(&mut source, &mut res, "passwd", policy, None).unwrap();
+            res
+        };
+
+        for staging in [Staging::Memory { limit: 1 << 20 }, Staging::TempFile] {
+            assert_eq!(read(staging), data);
+        }
+        // Read directly, the modification slips in between verifying and decrypting
+        assert_ne!(read(Staging::Direct), data);
+
+        let policy = DecryptPolicy {
+            staging: Staging::Memory { limit: 100 },
+            ..DecryptPolicy::default()
+        };
+        let res = read_container(
+            &mut io::Cursor::new(&container),
+            &mut Vec::new(),
+            "passwd",
+            policy,
+            None,
+        );
+        assert!(matches!(res, Err(Error::PolicyViolation(_))));
+    }
+
+    #[test]
+    fn single_pass_container() {
+        let data: Vec<u8> = (0..10_000_u32).map(|b| (b % 13) as u8).collect();
+        let settings = cipher::CryptSettings {
+            format: Format::SinglePass,
+            ..cipher::CryptSettings::default_for_testing()
+        };
+
+        let mut container = Vec::new();
+        create_container_from_reader(&mut &data[..], &mut container, "passwd", settings, None)
+            .unwrap();
+
+        for staging in [Staging::Direct, Staging::Memory { limit: 1 << 20 }] {
+            let policy = DecryptPolicy {
+                staging,
+                ..DecryptPolicy::default()
+            };
+            let mut res = Vec::new();
+            read_container(
+                &mut io::Cursor::new(&container),
+                &mut res,
+                "passwd",
+                policy,
+                None,
+            )
+            .unwrap();
+            assert_eq!(res, data);
+        }
+
+        let mut source = io::Cursor::new(&container);
+        let mut reader =
+            open_container(&mut source, "passwd", DecryptPolicy::default(), None).unwrap();
+        let mut res = Vec::new();
+        reader.read_to_end(&mut res).unwrap();
+        assert_eq!(res, data);
+
+        // Corruption is only detected at the end, staging withholds the data
+        let start = ZipArchive::new(io::Cursor::new(&container))
+            .unwrap()
+            .by_name("data.dat")
+            .unwrap()
+            .data_start();
+        let mut corrupted = container.clone();
+        corrupted[start as usize + 1000] ^= 1;
+        let policy = DecryptPolicy {
+            staging: Staging::Memory { limit: 1 << 20 },
+            ..DecryptPolicy::default()
+        };
+        let mut res = Vec::new();
+        assert!(matches!(
+            read_container(
+                &mut io::Cursor::new(corrupted),
+                &mut res,
+                "passwd",
+                policy,
+                None
+            ),
+            Err(Error::Corrupted)
+        ));
+        assert!(res.is_empty());
+
+        // A tampered salt is detected before anything is decrypted
+        let start = ZipArchive::new(io::Cursor::new(&container))
+            .unwrap()
+            .by_name("salt.dat")
+            .unwrap()
+            .data_start();
+        let mut tampered = container.clone();
+        tampered[start as usize + 5] ^= 1;
+        let mut res = Vec::new();
+        let status = read_container(
+            &mut io::Cursor::new(tampered),
+            &mut res,
+            "passwd",
+            DecryptPolicy::default(),
+            None,
+        );
+        assert!(matches!(status, Err(Error::HeaderTampered)));
+        assert!(res.is_empty());
+    }
+
+    #[test]
+    fn chunked_container() {
+        let data: Vec<u8> = (0..10_000_u32).map(|b| (b % 17) as u8).collect();
+        let settings = cipher::CryptSettings {
+            format: Format::Chunked,
+            mode: cipher::KeystreamMode::Counter,
+            ..cipher::CryptSettings::default_for_testing()
+        };
+
+        let mut container = Vec::new();
+        create_container(
+            &mut io::Cursor::new(&data),
+            &mut container,
+            "passwd",
+            settings,
+            None,
+        )
+        .unwrap();
+
+        let mut res = Vec::new();
+        read_container(
+            &mut io::Cursor::new(&container),
+            &mut res,
+            "passwd",
+            DecryptPolicy::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(res, data);
+
+        let mut source = io::Cursor::new(&container);
+        let mut reader =
+            open_container(&mut source, "passwd", DecryptPolicy::default(), None).unwrap();
+        reader.seek(SeekFrom::Start(9_000)).unwrap();
+        let mut res = Vec::new();
+        reader.read_to_end(&mut res).unwrap();
+        assert_eq!(res, data[9_000..]);
+        drop(reader);
+
+        let start = ZipArchive::new(io::Cursor::new(&container))
+            .unwrap()
+            .by_name("data.dat")
+            .unwrap()
+            .data_start();
+        container[start as usize + 1000] ^= 1;
+        let err = read_container(
+            &mut io::Cursor::new(container),
+            &mut Vec::new(),
+            "passwd",
+            DecryptPolicy::default(),
+            None,
+        )
+        .unwrap_err();
+        assert!(matches!(err, Error::CorruptedChunk(0)));
+    }
+
+    #[test]
+    fn container_corrupted_data() {
+        let data: Vec<u8> = (0..10_u64.pow(4)).map(|b| b as u8).collect();
+        let mut container = io::Cursor::new(Vec::<u8>::new());
+        create_container(
+            &mut io::Cursor::new(data),
+            &mut container,
+            "passwd",
+            cipher::CryptSettings::default_for_testing(),
+            None,
+        )
+        .unwrap();
+
+        // The salt of the `Balloon` is authenticated along with the data
+        for (name, offset) in [("data.dat", 1000), ("salt.dat", 5)] {
+            let start = ZipArchive::new(&mut container)
+                .unwrap()
+                .by_name(name)
+                .unwrap()
+                .data_start();
+            let mut corrupted = container.get_ref().clone();
+            corrupted[(start + offset) as usize] ^= 1;
+
+            let mut res = Vec::new();
+            let err = read_container(
+                &mut io::Cursor::new(corrupted),
+                &mut res,
+                "passwd",
+                DecryptPolicy::default(),
+                None,
+            )
+            .unwrap_err();
+
+            // Nothing is written since data is authenticated before decrypting it
+            assert!(matches!(err, Error::Corrupted), "{name}");
+            assert!(res.is_empty());
+        }
+    }
+
+    #[test]
+    fn container_with_pepper() {
+        let pepper = Pepper::new([7; 32]);
+        let settings = cipher::CryptSettings::default_for_testing().with_pepper();
+
+        let mut container = io::Cursor::new(Vec::<u8>::new());
+        let res = create_container(
+            &mut io::Cursor::new(b"data".to_vec()),
+            &mut container,
+            "passwd",
+            settings,
+            None,
+        );
+        assert!(matches!(res, Err(Error::MissingPepper)));
+
+        let mut container = io::Cursor::new(Vec::<u8>::new());
+        create_container(
+            &mut io::Cursor::new(b"data".to_vec()),
+            &mut container,
+            Peppered::new("passwd", &pepper),
+            settings,
+            None,
+        )
+        .unwrap();
+
+        let read = |pepper: Option<&Pepper>| {
+            let key = Peppered::with("passwd", pepper);
+            let mut res = Vec::new();
+            let mut container = io::Cursor::new(container.get_ref());
+            read_container(
+                &mut container,
+                &mut res,
+                key,
+                DecryptPolicy::default(),
+                None,
+            )
+            .map(|_| res)
+        };
+
+        assert_eq!(read(Some(&pepper)).unwrap(), b"data");
+        assert!(matches!(read(None), Err(Error::MissingPepper)));
+        assert!(matches!(
+            read(Some(&Pepper::new([8; 32]))),
+            Err(Error::WrongPassword)
+        ));
+    }
+
+    /// Encrypts some data into a container with `settings`, checks that it decrypts
+    /// again and returns the metadata of the container.
+    fn round_trip(settings: cipher::CryptSettings) -> ContainerMetadata {
+        let mut container = io::Cursor::new(Vec::<u8>::new());
+        create_container(
+            &mut io::Cursor::new(b"data".to_vec()),
+            &mut container,
+            "passwd",
+            settings,
+            None,
+        )
+        .unwrap();
+        container.rewind().unwrap();
+        let metadata = read_metadata(&mut container).unwrap();
+
+        let mut res = Vec::new();
+        read_container(
+            &mut container,
+            &mut res,
+            "passwd",
+            DecryptPolicy::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(res, b"data");
+        metadata
+    }
+
+    #[test]
+    fn container_with_each_kdf() {
+        use crate::kdf::{BalloonSettings, Pbkdf2Settings, ScryptSettings};
+
+        let kdfs = [
+            KdfSettings::Scrypt(ScryptSettings {
+                log_n: 8,
+                r: 8,
+                p: 1,
+            }),
+            KdfSettings::Pbkdf2Sha512(Pbkdf2Settings { rounds: 1000 }),
+            KdfSettings::Balloon(BalloonSettings {
+                s_cost: 1000,
+                t_cost: 2,
+                step_delta: 3,
+                revision: crate::hash::Revision::latest(),
+            }),
+        ];
+        for kdf in kdfs {
+            let settings = cipher::CryptSettings {
+                kdf,
+                ..cipher::CryptSettings::default_for_testing()
+            };
+            // The KDF is picked from the metadata
+            assert_eq!(round_trip(settings).settings().kdf, kdf);
+        }
+    }
+
+    #[test]
+    fn container_records_preset() {
+        let settings = cipher::CryptSettings {
+            preset: Some(Preset::InteractiveV1),
+            ..cipher::CryptSettings::default_for_testing()
+        };
+
+        let mut container = io::Cursor::new(Vec::<u8>::new());
+        create_container(
+            &mut io::Cursor::new(b"data".to_vec()),
+            &mut container,
+            "passwd",
+            settings,
+            None,
+        )
+        .unwrap();
+        container.rewind().unwrap();
+
+        let metadata = read_metadata(&mut container).unwrap();
+        assert_eq!(metadata.settings(), &settings);
+        assert_eq!(metadata.version(), env!("CARGO_PKG_VERSION"));
+        // The label does not match the settings
+        assert_eq!(metadata.preset(), None);
+
+        let metadata = ContainerMetadata {
+            settings: Preset::InteractiveV1.settings().with_wrapped_key(),
+            ..metadata
+        };
+        assert_eq!(metadata.preset(), Some(Preset::InteractiveV1));
+    }
+
+    #[test]
+    fn container_with_each_indexing() {
+        use crate::hash::Indexing;
+
+        for indexing in [Indexing::Independent, Indexing::Dependent, Indexing::Hybrid] {
+            let settings = cipher::CryptSettings {
+                indexing,
+                ..cipher::CryptSettings::default_for_testing()
+            };
+            assert_eq!(round_trip(settings).settings().indexing, indexing);
+        }
+    }
+
+    #[test]
+    fn container_with_each_hash() {
+        use crate::hash::HashFunction;
+
+        for hash in [
+            HashFunction::Sha3_512,
+            HashFunction::Blake2b512,
+            HashFunction::Sha512,
+        ] {
+            let settings = cipher::CryptSettings {
+                hash,
+                ..cipher::CryptSettings::default_for_testing()
+            };
+            assert_eq!(round_trip(settings).settings().hash, hash);
+        }
+    }
+
+    #[test]
+    fn pipelined_container() {
+        let data: Vec<u8> = (0..200_000_u32).map(|b| b as u8).collect();
+        let settings = cipher::CryptSettings::default_for_testing();
+
+        let mut container = io::Cursor::new(Vec::<u8>::new());
+        create_container_pipelined(
+            &mut io::Cursor::new(data.clone()),
+            &mut container,
+            "passwd",
+            settings,
+            None,
+        )
+        .unwrap();
+        container.rewind().unwrap();
+
+        for pipelined in [false, true] {
+            let mut res = Vec::new();
+            let policy = DecryptPolicy {
+                pipelined,
+                ..DecryptPolicy::default()
+            };
+            read_container(&mut container, &mut res, "passwd", policy, None).unwrap();
+            assert_eq!(res, data);
+        }
+    }
+
+    #[test]
+    fn change_password_of_wrapped_container() {
+        let src = temp_dir();
+        fs::write(src.join("first"), b"first entry").unwrap();
+        fs::write(src.join("second"), b"second entry").unwrap();
+        let settings = cipher::CryptSettings::default_for_testing().with_wrapped_key();
+
+        let mut container = io::Cursor::new(Vec::<u8>::new());
+        create_container_from_dir(&src, &mut container, "old", settings, None).unwrap();
+        let original = container.get_ref().clone();
+
+        change_password(&mut container, "old", "new", DecryptPolicy::default(), None).unwrap();
+        assert!(matches!(
+            change_password(&mut container, "old", "new", DecryptPolicy::default(), None),
+            Err(Error::WrongPassword)
+        ));
+
+        // Only `key.dat` differs, the zip crate checks the CRC of every file
+        let mut before = ZipArchive::new(io::Cursor::new(&original)).unwrap();
+        let mut after = ZipArchive::new(io::Cursor::new(container.get_ref())).unwrap();
+        for i in 0..before.len() {
+            let (mut old, mut new) = (Vec::new(), Vec::new());
+            let mut file = before.by_index(i).unwrap();
+            let name = file.name().to_string();
+            file.read_to_end(&mut old).unwrap();
+            after.by_name(&name).unwrap().read_to_end(&mut new).unwrap();
+            assert_eq!(old == new, name != "key.dat", "{name}");
+        }
+
+        let read = |password: &str| {
+            let mut res = Vec::new();
+            let mut container = io::Cursor::new(container.get_ref());
+            read_entry(
+                &mut container,
+                "second",
+                &mut res,
+                password,
+                DecryptPolicy::default(),
+                None,
+            )
+            .map(|_| res)
+        };
+        assert_eq!(read("new").unwrap(), b"second entry");
+        assert!(matches!(read("old"), Err(Error::WrongPassword)));
+
+        let dest = temp_dir();
+        container.rewind().unwrap();
+        extract_container(
+            &mut container,
+            &dest,
+            "new",
+            None,
+            DecryptPolicy::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(fs::read(dest.join("first")).unwrap(), b"first entry");
+
+        container.rewind().unwrap();
+        let mut reader = open_entry(
+            &mut container,
+            "first",
+            "new",
+            DecryptPolicy::default(),
+            None,
+        )
+        .unwrap();
+        let mut res = Vec::new();
+        reader.read_to_end(&mut res).unwrap();
+        assert_eq!(res, b"first entry");
+
+        fs::remove_dir_all(src).unwrap();
+        fs::remove_dir_all(dest).unwrap();
+    }
+
+    #[test]
+    fn change_password_requires_wrapped_key() {
+        let mut container = io::Cursor::new(Vec::<u8>::new());
+        create_container(
+            &mut io::Cursor::new(b"data".to_vec()),
+            &mut container,
+            "passwd",
+            cipher::CryptSettings::default_for_testing(),
+            None,
+        )
+        .unwrap();
+        let original = container.get_ref().clone();
+        assert!(matches!(
+            change_password(
+                &mut container,
+                "passwd",
+                "new",
+                DecryptPolicy::default(),
+                None
+            ),
+            Err(Error::InvalidSettings(_))
+        ));
+        assert_eq!(container.get_ref(), &original);
     }
 }
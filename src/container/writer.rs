@@ -0,0 +1,302 @@
+//! Minimal zip writer that never seeks, so containers can be written to pipes,
+//! stdout or sockets. Every file is stored uncompressed and followed by a data
+//! descriptor holding its CRC and size, which are unknown when it is started.
+//! Additionally `overwrite_file` replaces the contents of such a file in place.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crc32fast::Hasher;
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR: u32 = 0x0807_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+
+/// Unix, zip specification 4.6
+const VERSION_MADE_BY: u16 = 0x032e;
+/// Version 2.0, required for data descriptors
+const VERSION_NEEDED: u16 = 20;
+/// Bit 3: CRC and sizes follow the data in a data descriptor
+const FLAGS: u16 = 0x0008;
+/// 1980-01-01 00:00:00, so that containers do not depend on the time of creation
+const DOS_TIME: u16 = 0;
+const DOS_DATE: u16 = (1 << 5) | 1;
+/// Regular file with permissions `rw-r--r--`
+const EXTERNAL_ATTRIBUTES: u32 = 0o100644 << 16;
+
+/// File already written, remembered for the central directory.
+struct Entry {
+    name: String,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+/// Writes a zip archive to `inner` strictly sequentially.
+/// Like `zip::ZipWriter` without the `large_file` option, files and archives
+/// larger than 4 GiB are rejected.
+pub(super) struct StreamingZipWriter<W> {
+    inner: W,
+    entries: Vec<Entry>,
+    /// File currently being written
+    current: Option<(Entry, Hasher)>,
+    written: u64,
+    comment: String,
+}
+
+impl<W: Write> StreamingZipWriter<W> {
+    pub(super) fn new(inner: W) -> Self {
+        Self {
+            inner,
+            entries: Vec::new(),
+            current: None,
+            written: 0,
+            comment: String::new(),
+        }
+    }
+
+    pub(super) fn set_comment(&mut self, comment: impl Into<String>) {
+        self.comment = comment.into();
+    }
+
+    /// Finishes the current file and starts a new one called `name`.
+    pub(super) fn start_file(&mut self, name: impl Into<String>) -> io::Result<()> {
+        self.finish_file()?;
+        let name = name.into();
+        let offset = small(self.written, "Archive")?;
+
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend(LOCAL_HEADER.to_le_bytes());
+        header.extend(VERSION_NEEDED.to_le_bytes());
+        header.extend(FLAGS.to_le_bytes());
+        header.extend(0_u16.to_le_bytes()); // stored
+        header.extend(DOS_TIME.to_le_bytes());
+        header.extend(DOS_DATE.to_le_bytes());
+        header.extend([0; 12]); // CRC and sizes, see data descriptor
+        header.extend(small_u16(name.len(), "File name")?.to_le_bytes());
+        header.extend(0_u16.to_le_bytes()); // extra field
+        header.extend(name.as_bytes());
+        self.write_raw(&header)?;
+
+        let entry = Entry {
+            name,
+            crc: 0,
+            size: 0,
+            offset,
+        };
+        self.current = Some((entry, Hasher::new()));
+        Ok(())
+    }
+
+    /// Writes the data descriptor of the current file, if any.
+    fn finish_file(&mut self) -> io::Result<()> {
+        let Some((mut entry, hasher)) = self.current.take() else {
+            return Ok(());
+        };
+        entry.crc = hasher.finalize();
+
+        let mut descriptor = Vec::with_capacity(16);
+        descriptor.extend(DATA_DESCRIPTOR.to_le_bytes());
+        descriptor.extend(entry.crc.to_le_bytes());
+        descriptor.extend(entry.size.to_le_bytes()); // compressed
+        descriptor.extend(entry.size.to_le_bytes()); // uncompressed
+        self.write_raw(&descriptor)?;
+
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Finishes the archive by writing the central directory and returns `inner`.
+    pub(super) fn finish(mut self) -> io::Result<W> {
+        self.finish_file()?;
+        let start = small(self.written, "Archive")?;
+
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            directory.extend(CENTRAL_HEADER.to_le_bytes());
+            directory.extend(VERSION_MADE_BY.to_le_bytes());
+            directory.extend(VERSION_NEEDED.to_le_bytes());
+            directory.extend(FLAGS.to_le_bytes());
+            directory.extend(0_u16.to_le_bytes()); // stored
+            directory.extend(DOS_TIME.to_le_bytes());
+            directory.extend(DOS_DATE.to_le_bytes());
+            directory.extend(entry.crc.to_le_bytes());
+            directory.extend(entry.size.to_le_bytes()); // compressed
+            directory.extend(entry.size.to_le_bytes()); // uncompressed
+            directory.extend(small_u16(entry.name.len(), "File name")?.to_le_bytes());
+            directory.extend(0_u16.to_le_bytes()); // extra field
+            directory.extend(0_u16.to_le_bytes()); // comment
+            directory.extend(0_u16.to_le_bytes()); // disk
+            directory.extend(0_u16.to_le_bytes()); // internal attributes
+            directory.extend(EXTERNAL_ATTRIBUTES.to_le_bytes());
+            directory.extend(entry.offset.to_le_bytes());
+            directory.extend(entry.name.as_bytes());
+        }
+        let len = small(directory.len() as u64, "Central directory")?;
+        let count = small_u16(self.entries.len(), "Number of files")?;
+
+        directory.extend(END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+        directory.extend(0_u16.to_le_bytes()); // disk
+        directory.extend(0_u16.to_le_bytes()); // disk of central directory
+        directory.extend(count.to_le_bytes()); // on this disk
+        directory.extend(count.to_le_bytes()); // in total
+        directory.extend(len.to_le_bytes());
+        directory.extend(start.to_le_bytes());
+        directory.extend(small_u16(self.comment.len(), "Comment")?.to_le_bytes());
+        directory.extend(self.comment.as_bytes());
+        self.write_raw(&directory)?;
+
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    /// Writes `data` outside of any file.
+    fn write_raw(&mut self, data: &[u8]) -> io::Result<()> {
+        self.inner.write_all(data)?;
+        self.written += data.len() as u64;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for StreamingZipWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some((entry, hasher)) = &mut self.current else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No file has been started",
+            ));
+        };
+        if entry.size as u64 + buf.len() as u64 > u32::MAX as u64 {
+            return Err(too_large("File"));
+        }
+        let n = self.inner.write(buf)?;
+        hasher.update(&buf[..n]);
+        entry.size += n as u32;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Replaces the contents of a file written by `StreamingZipWriter` with `data`
+/// of the same length, updating its CRC in the data descriptor and the central
+/// directory. Nothing is written unless both are found where expected.
+pub(super) fn overwrite_file<F: Read + Write + Seek>(
+    archive: &mut F,
+    data_start: u64,
+    central_header_start: u64,
+    data: &[u8],
+) -> io::Result<()> {
+    let descriptor = data_start + data.len() as u64;
+    for (offset, expected) in [
+        (descriptor, DATA_DESCRIPTOR),
+        (central_header_start, CENTRAL_HEADER),
+    ] {
+        let mut signature = [0_u8; 4];
+        archive.seek(SeekFrom::Start(offset))?;
+        archive.read_exact(&mut signature)?;
+        if u32::from_le_bytes(signature) != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "File was not written by `StreamingZipWriter`",
+            ));
+        }
+    }
+
+    let crc = crc32fast::hash(data).to_le_bytes();
+    archive.seek(SeekFrom::Start(data_start))?;
+    archive.write_all(data)?;
+    archive.seek(SeekFrom::Start(descriptor + 4))?;
+    archive.write_all(&crc)?;
+    // Signature, versions, flags, method, time and date precede the CRC
+    archive.seek(SeekFrom::Start(central_header_start + 16))?;
+    archive.write_all(&crc)?;
+    archive.flush()
+}
+
+fn too_large(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{what} is too large for a zip archive without ZIP64"),
+    )
+}
+
+/// Checks that `value` fits the 32 bit fields of the zip format.
+fn small(value: u64, what: &str) -> io::Result<u32> {
+    u32::try_from(value).map_err(|_| too_large(what))
+}
+
+/// Checks that `value` fits the 16 bit fields of the zip format.
+fn small_u16(value: usize, what: &str) -> io::Result<u16> {
+    u16::try_from(value).map_err(|_| too_large(what))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use zip::ZipArchive;
+
+    use super::*;
+
+    /// Archives written without seeking must be readable by the `zip` crate,
+    /// which also checks the CRC of every file.
+    #[test]
+    fn readable_by_zip() {
+        let mut writer = StreamingZipWriter::new(Vec::new());
+        writer.set_comment("comment");
+        writer.start_file("empty").unwrap();
+        writer.start_file("dir/data.dat").unwrap();
+        let data: Vec<u8> = (0..100_000_u32).map(|b| (b % 13) as u8).collect();
+        writer.write_all(&data).unwrap();
+        writer.start_file("last").unwrap();
+        writer.write_all(b"last").unwrap();
+        let archive = writer.finish().unwrap();
+
+        let mut zip = ZipArchive::new(Cursor::new(archive)).unwrap();
+        assert_eq!(zip.comment(), b"comment");
+        assert_eq!(zip.len(), 3);
+        for (name, expected) in [
+            ("empty", &[][..]),
+            ("dir/data.dat", &data),
+            ("last", b"last"),
+        ] {
+            let mut res = Vec::new();
+            zip.by_name(name).unwrap().read_to_end(&mut res).unwrap();
+            assert_eq!(res, expected);
+        }
+    }
+
+    #[test]
+    fn overwrite_in_place() {
+        let mut writer = StreamingZipWriter::new(Vec::new());
+        writer.start_file("first").unwrap();
+        writer.write_all(b"first").unwrap();
+        writer.start_file("key").unwrap();
+        writer.write_all(b"old key").unwrap();
+        let mut archive = Cursor::new(writer.finish().unwrap());
+
+        let mut zip = ZipArchive::new(&mut archive).unwrap();
+        let file = zip.by_name("key").unwrap();
+        let (start, central) = (file.data_start(), file.central_header_start());
+        drop(file);
+        overwrite_file(&mut archive, start, central, b"new key").unwrap();
+        // The archive starts with a local header, not a central one
+        assert!(overwrite_file(&mut archive, start, 0, b"new key").is_err());
+
+        // `by_name` checks the CRC in the central directory
+        let mut zip = ZipArchive::new(archive).unwrap();
+        let mut res = Vec::new();
+        zip.by_name("key").unwrap().read_to_end(&mut res).unwrap();
+        assert_eq!(res, b"new key");
+    }
+
+    #[test]
+    fn write_before_start() {
+        let mut writer = StreamingZipWriter::new(Vec::new());
+        assert!(writer.write_all(b"data").is_err());
+    }
+}
@@ -0,0 +1,118 @@
+//! This module contains the `Error` type returned by all fallible operations of this crate.
+
+use std::collections::TryReserveError;
+use std::fmt;
+use std::io;
+
+use zip::result::ZipError;
+
+use crate::container::PolicyViolation;
+
+/// Convenience alias for results with this crate's `Error`.
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading from the source or writing to the destination failed.
+    Io(io::Error),
+    /// The password does not match. For `Format::Legacy` this can not be
+    /// told apart from corrupted data and is reported for both.
+    /// Unless `Format::EncryptThenMac` or `Format::SinglePass` is used, the salt
+    /// is recovered from the whole ciphertext, so corrupted data usually results in this error as well.
+    WrongPassword,
+    /// The password matches but the MAC of the data does not.
+    Corrupted,
+    /// The tag of the chunk with this index does not match, because the chunk
+    /// has been modified, moved or cut short. Only for `Format::Chunked`.
+    CorruptedChunk(u64),
+    /// The stream ends before its last chunk. Only for `Format::Chunked`.
+    Truncated,
+    /// The metadata of the container has been modified.
+    HeaderTampered,
+    /// The container was created by a newer version using unknown features.
+    UnsupportedVersion(String),
+    /// A required file, e.g. `salt.dat`, is missing from the container.
+    MissingEntry(String),
+    /// The entry name would leave the directory it is extracted into.
+    InvalidEntryName(String),
+    /// The container is not a valid zip archive or has invalid metadata.
+    InvalidContainer(String),
+    /// The settings of the container exceed the `DecryptPolicy`.
+    PolicyViolation(PolicyViolation),
+    /// The settings can not be used, e.g. because they exceed the limits of this platform.
+    InvalidSettings(String),
+    /// A known-answer test vector does not match the output of this implementation.
+    TestVectorMismatch(String),
+    /// The password could not be derived, e.g. because of invalid parameters.
+    KeyDerivation(String),
+    /// There is no `Preset` with this name.
+    UnknownPreset(String),
+    /// The container requires a `Pepper` that has not been provided.
+    MissingPepper,
+    /// Memory for the `Balloon` could not be allocated.
+    OutOfMemory(TryReserveError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {e}"),
+            Error::WrongPassword => write!(f, "Wrong password"),
+            Error::Corrupted => write!(f, "Data has been corrupted"),
+            Error::CorruptedChunk(index) => write!(f, "Chunk {index} has been corrupted"),
+            Error::Truncated => write!(f, "Data has been truncated"),
+            Error::HeaderTampered => write!(f, "Header has been tampered with"),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "Container created by unsupported version {version}")
+            }
+            Error::MissingEntry(name) => write!(f, "Container has no `{name}`"),
+            Error::InvalidEntryName(name) => write!(f, "Invalid entry name `{name}`"),
+            Error::InvalidContainer(reason) => write!(f, "Invalid container: {reason}"),
+            Error::PolicyViolation(violation) => violation.fmt(f),
+            Error::InvalidSettings(reason) => write!(f, "Invalid settings: {reason}"),
+            Error::TestVectorMismatch(name) => write!(f, "Test vector `{name}` does not match"),
+            Error::KeyDerivation(reason) => write!(f, "Unable to derive password: {reason}"),
+            Error::UnknownPreset(name) => write!(f, "Unknown preset `{name}`"),
+            Error::MissingPepper => write!(f, "Pepper required but not provided"),
+            Error::OutOfMemory(e) => write!(f, "Out of memory: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::PolicyViolation(violation) => Some(violation),
+            Error::OutOfMemory(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<ZipError> for Error {
+    fn from(e: ZipError) -> Self {
+        match e {
+            ZipError::Io(e) => Error::Io(e),
+            e => Error::InvalidContainer(e.to_string()),
+        }
+    }
+}
+
+impl From<PolicyViolation> for Error {
+    fn from(violation: PolicyViolation) -> Self {
+        Error::PolicyViolation(violation)
+    }
+}
+
+impl From<TryReserveError> for Error {
+    fn from(e: TryReserveError) -> Self {
+        Error::OutOfMemory(e)
+    }
+}
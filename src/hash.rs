@@ -1,21 +1,423 @@
 //! This module contains a version of Balloon-hashing that allows it to be modified into a stream cipher.
+//! `Balloon` is generic over the underlying hash function, `HashFunction` selects one of them.
+//! Additionally provides `Kmac256` which is used for authentication.
 
+use crate::error::{Error, Result};
 use crate::progress::Progress;
-use sha3::{digest::FixedOutputReset, Digest, Sha3_512};
+use serde::{Deserialize, Serialize};
+use sha3::digest::{self, consts::U64, ExtendableOutput, OutputSizeUser, XofReader};
+use sha3::{digest::FixedOutputReset, CShake256, CShake256Core, Digest, Sha3_512};
+use zeroize::{Zeroize, Zeroizing};
 
-/// Balloon hasher state.
-#[derive(Debug)]
-pub struct Balloon {
+/// Revision of the Balloon algorithm. Older revisions are kept for decryption.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum Revision {
+    /// Encodes integers in native byte order, so the output depends on the host.
+    V0,
+    /// Encodes integers in little endian byte order.
+    V1,
+    /// Like `V1` but mixes every block with its predecessor,
+    /// `V0` and `V1` mix it with itself instead.
+    V2,
+}
+
+impl Revision {
+    /// Revision of Balloons created before it was recorded.
+    pub(crate) fn legacy() -> Self {
+        Self::V0
+    }
+
+    /// Revision used for new Balloons.
+    pub fn latest() -> Self {
+        Self::V2
+    }
+
+    #[inline]
+    fn encode(&self, val: u64) -> [u8; 8] {
+        match self {
+            Revision::V0 => val.to_ne_bytes(),
+            Revision::V1 | Revision::V2 => val.to_le_bytes(),
+        }
+    }
+
+    #[inline]
+    fn decode(&self, arr: [u8; 8]) -> u64 {
+        match self {
+            Revision::V0 => u64::from_ne_bytes(arr),
+            Revision::V1 | Revision::V2 => u64::from_le_bytes(arr),
+        }
+    }
+}
+
+/// How `Balloon` picks the other blocks mixed into the current one.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum Indexing {
+    /// Indices only depend on the salt and a counter, like Balloon-i.
+    /// The memory access pattern leaks nothing about the password through cache timing.
+    Independent,
+    /// Indices depend on the contents of the buffer, like Argon2d.
+    /// Maximizes the cost of trading memory for time, but the memory access
+    /// pattern depends on the password.
+    Dependent,
+    /// `Independent` for the first half of the first round, `Dependent` afterwards,
+    /// like Argon2id.
+    Hybrid,
+}
+
+impl Indexing {
+    /// Indexing of Balloons created before it was recorded.
+    pub(crate) fn legacy() -> Self {
+        Self::Independent
+    }
+}
+
+/// Variant of the Balloon algorithm, consisting of `Revision`, `Indexing`
+/// and the number of lanes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Variant {
+    pub revision: Revision,
+    pub indexing: Indexing,
+    /// Number of lanes the buffer is split into, which are filled and mixed in
+    /// parallel like Argon2's lanes. With a single lane everything is sequential.
+    pub p_cost: usize,
+}
+
+impl std::default::Default for Variant {
+    /// Latest revision using data-independent indexing and a single lane.
+    fn default() -> Self {
+        Self {
+            revision: Revision::latest(),
+            indexing: Indexing::Independent,
+            p_cost: 1,
+        }
+    }
+}
+
+/// Hash function with 64 byte output that `Balloon` can be built on.
+pub trait BalloonHash: Digest + FixedOutputReset + OutputSizeUser<OutputSize = U64> + Send {}
+
+impl<D> BalloonHash for D where
+    D: Digest + FixedOutputReset + OutputSizeUser<OutputSize = U64> + Send
+{
+}
+
+/// Hash functions shipped for `Balloon`, recorded in every container.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum HashFunction {
+    #[serde(rename = "SHA3-512")]
+    Sha3_512,
+    #[serde(rename = "BLAKE2b-512")]
+    Blake2b512,
+    #[serde(rename = "SHA-512")]
+    Sha512,
+}
+
+impl HashFunction {
+    /// Hash function of Balloons created before it was recorded.
+    pub(crate) fn legacy() -> Self {
+        Self::Sha3_512
+    }
+}
+
+/// Number of segments every lane is split into if there are several lanes.
+/// Lanes only reference blocks of other lanes outside the segment currently being mixed.
+const SYNC_POINTS: usize = 4;
+
+/// Balloon hasher state using the hash function `D`. It is wiped when dropped.
+pub struct Balloon<D: BalloonHash = Sha3_512> {
     buffer: Vec<[u8; 64]>,
-    hash: Sha3_512,    // so that we don't have to generate new hasher every step
+    state: Lane<D>,    // state for stepping after creation
     salt: Vec<u8>,     // we need to remember salt for every step
     step_delta: usize, // how many samples to take every step
-    cnt: u64,          // used to increase hash complexity
     pos: usize,        // current position for stepping
+    revision: Revision,
+    indexing: Indexing,
+    p_cost: usize,
+    locked: bool, // whether `buffer` is locked in memory
+}
+
+impl<D: BalloonHash> std::fmt::Debug for Balloon<D> {
+    /// Only shows the parameters, never the state.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Balloon")
+            .field("s_cost", &self.buffer.len())
+            .field("step_delta", &self.step_delta)
+            .field("revision", &self.revision)
+            .field("indexing", &self.indexing)
+            .field("p_cost", &self.p_cost)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<D: BalloonHash> Drop for Balloon<D> {
+    fn drop(&mut self) {
+        // Zeroizing the `Vec` itself would truncate it, so `unlock` would see
+        // an empty buffer and leave the memory locked.
+        for block in self.buffer.iter_mut() {
+            block.zeroize();
+        }
+        self.salt.zeroize();
+        if self.locked {
+            unlock(&self.buffer);
+        }
+    }
+}
+
+/// Locks the memory of `buffer` so that it is never swapped to disk.
+/// This is best effort, returns whether it succeeded.
+#[cfg(all(feature = "mlock", unix))]
+fn lock(buffer: &[[u8; 64]]) -> bool {
+    let len = std::mem::size_of_val(buffer);
+    // SAFETY: the range is a valid allocation, locking does not modify it.
+    len > 0 && unsafe { libc::mlock(buffer.as_ptr().cast(), len) } == 0
+}
+
+#[cfg(not(all(feature = "mlock", unix)))]
+fn lock(_buffer: &[[u8; 64]]) -> bool {
+    false
+}
+
+/// Reverts `lock`, must be called before the memory is freed.
+#[cfg(all(feature = "mlock", unix))]
+fn unlock(buffer: &[[u8; 64]]) {
+    let len = std::mem::size_of_val(buffer);
+    // SAFETY: the range was locked by `lock` and is still allocated.
+    unsafe {
+        libc::munlock(buffer.as_ptr().cast(), len);
+    }
+}
+
+#[cfg(not(all(feature = "mlock", unix)))]
+fn unlock(_buffer: &[[u8; 64]]) {}
+
+/// Parameters shared by all lanes.
+struct Params<'a> {
+    salt: &'a [u8],
+    step_delta: usize,
+    variant: Variant,
+}
+
+/// Blocks of a lane before and after the segment currently worked on.
+type Outside<'a> = (&'a [[u8; 64]], &'a [[u8; 64]]);
+
+/// Blocks visible to a lane while it works on one segment: its own segment
+/// mutably and the blocks outside the current segment of every lane immutably.
+struct View<'a, 'b> {
+    lane: usize,
+    start: usize,
+    current: &'a mut [[u8; 64]],
+    /// Blocks before and after the current segment, for every lane.
+    outside: &'b [Outside<'b>],
+}
+
+impl View<'_, '_> {
+    fn lane_len(&self) -> usize {
+        self.start + self.current.len() + self.outside[self.lane].1.len()
+    }
+
+    fn positions(&self) -> std::ops::Range<usize> {
+        self.start..self.start + self.current.len()
+    }
+
+    #[inline]
+    fn get(&self, lane: usize, pos: usize) -> [u8; 64] {
+        let (before, after) = self.outside[lane];
+        let end = self.start + self.current.len();
+        if pos < self.start {
+            before[pos]
+        } else if pos >= end {
+            after[pos - end]
+        } else {
+            debug_assert_eq!(lane, self.lane, "segment of another lane");
+            self.current[pos - self.start]
+        }
+    }
+
+    #[inline]
+    fn set(&mut self, pos: usize, block: [u8; 64]) {
+        self.current[pos - self.start] = block;
+    }
+
+    /// Picks the block to mix in from the hash `tmp`.
+    fn reference(&self, tmp: &[u8; 64], revision: Revision) -> (usize, usize) {
+        let decode = |bytes: &[u8]| revision.decode(bytes.try_into().unwrap());
+        let len = self.lane_len() as u64;
+        let lanes = self.outside.len();
+        let lane = if lanes == 1 {
+            self.lane
+        } else {
+            (decode(&tmp[8..16]) % lanes as u64) as usize
+        };
+        if lane == self.lane {
+            return (lane, (decode(&tmp[0..8]) % len) as usize);
+        }
+
+        // The current segment of other lanes is being overwritten, skip it
+        let skip = self.current.len();
+        let mut pos = (decode(&tmp[0..8]) % (len - skip as u64)) as usize;
+        if pos >= self.start {
+            pos += skip;
+        }
+        (lane, pos)
+    }
+}
+
+/// State of a single lane while filling and mixing the buffer.
+struct Lane<D> {
+    hash: D,    // so that we don't have to generate new hasher every step
+    cnt: u64,   // used to increase hash complexity
+    steps: u64, // number of steps so far, used for hybrid indexing
+}
+
+impl<D: BalloonHash> Lane<D> {
+    fn new() -> Self {
+        Self {
+            hash: D::new(),
+            cnt: 0,
+            steps: 0,
+        }
+    }
+
+    /// Fills the current segment, the first block is derived from password and salt.
+    fn fill(&mut self, view: &mut View, passwd: &[u8], params: &Params) {
+        let revision = params.variant.revision;
+        for pos in view.positions() {
+            Digest::update(&mut self.hash, revision.encode(self.cnt));
+            self.cnt += 1;
+            if pos == 0 {
+                Digest::update(&mut self.hash, passwd);
+                Digest::update(&mut self.hash, params.salt);
+                if params.variant.p_cost > 1 {
+                    Digest::update(&mut self.hash, revision.encode(view.lane as u64));
+                }
+            } else {
+                Digest::update(&mut self.hash, view.get(view.lane, pos - 1));
+            }
+            view.set(pos, self.hash.finalize_fixed_reset().into());
+        }
+    }
+
+    /// Mixes the block at `pos`. Doing this for every block equals one round of buffer mixing.
+    ///
+    /// # Returns
+    /// * The block after mixing.
+    fn mix(&mut self, view: &mut View, pos: usize, params: &Params, prog: &Progress) -> [u8; 64] {
+        prog.inc();
+
+        let Variant {
+            revision, indexing, ..
+        } = params.variant;
+        let lane = view.lane;
+        let len = view.lane_len();
+
+        // instead of "buffer[(pos as i64 - 1).rem_euclid(len as i64) as usize]"
+        let prev = if pos == 0 {
+            view.get(lane, len - 1)
+        } else if revision == Revision::V2 {
+            view.get(lane, pos - 1)
+        } else {
+            // Older revisions mistakenly use the current block
+            view.get(lane, pos)
+        };
+        Digest::update(&mut self.hash, prev);
+        Digest::update(&mut self.hash, revision.encode(self.cnt));
+        self.cnt += 1;
+        Digest::update(&mut self.hash, view.get(lane, pos));
+        view.set(pos, self.hash.finalize_fixed_reset().into());
+
+        let dependent = match indexing {
+            Indexing::Independent => false,
+            Indexing::Dependent => true,
+            Indexing::Hybrid => self.steps >= len as u64 / 2,
+        };
+        self.steps += 1;
+
+        for i in 0..params.step_delta {
+            Digest::update(&mut self.hash, revision.encode(i as u64));
+            Digest::update(&mut self.hash, revision.encode(self.cnt));
+            self.cnt += 1;
+            if dependent {
+                Digest::update(&mut self.hash, view.get(lane, pos));
+            } else {
+                Digest::update(&mut self.hash, params.salt);
+            }
+
+            let tmp: [u8; 64] = self.hash.finalize_fixed_reset().into();
+            let (other_lane, other) = view.reference(&tmp, revision);
+
+            Digest::update(&mut self.hash, revision.encode(self.cnt));
+            self.cnt += 1;
+            Digest::update(&mut self.hash, view.get(lane, pos));
+            Digest::update(&mut self.hash, view.get(other_lane, other));
+
+            view.set(pos, self.hash.finalize_fixed_reset().into());
+        }
+
+        view.get(lane, pos)
+    }
+}
+
+/// Runs `work` on `segment` of every lane, in parallel if there are several lanes.
+fn for_each_lane<D, F>(
+    buffer: &mut [[u8; 64]],
+    lanes: &mut [Lane<D>],
+    segment: usize,
+    segments: usize,
+    work: F,
+) where
+    D: BalloonHash,
+    F: Fn(&mut Lane<D>, &mut View) + Sync,
+{
+    let lane_len = buffer.len() / lanes.len();
+    let start = segment * lane_len / segments;
+    let end = (segment + 1) * lane_len / segments;
+
+    let mut outside = Vec::with_capacity(lanes.len());
+    let mut currents = Vec::with_capacity(lanes.len());
+    for blocks in buffer.chunks_mut(lane_len) {
+        let (before, rest) = blocks.split_at_mut(start);
+        let (current, after) = rest.split_at_mut(end - start);
+        outside.push((&*before, &*after));
+        currents.push(current);
+    }
+    let mut jobs: Vec<_> = lanes
+        .iter_mut()
+        .zip(currents)
+        .enumerate()
+        .map(|(lane, (state, current))| {
+            let view = View {
+                lane,
+                start,
+                current,
+                outside: &outside,
+            };
+            (state, view)
+        })
+        .collect();
+
+    if let [(state, view)] = &mut jobs[..] {
+        return work(state, view);
+    }
+    let workers = std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(jobs.len());
+    let per_worker = jobs.len().div_ceil(workers);
+    let work = &work;
+    std::thread::scope(|scope| {
+        for chunk in jobs.chunks_mut(per_worker) {
+            scope.spawn(move || {
+                for (state, view) in chunk {
+                    work(state, view);
+                }
+            });
+        }
+    });
 }
 
 impl Balloon {
-    /// Create a new Balloon-Hash instance.
+    /// Create a new Balloon-Hash instance using the legacy `Revision::V0` for compatibility,
+    /// new code should use `with_revision` or `with_variant` with `Variant::default()`.
+    /// Fails without panicking if the buffer of `s_cost` blocks can not be allocated.
     pub fn new(
         passwd: impl AsRef<[u8]>,
         salt: Vec<u8>,
@@ -23,49 +425,146 @@ impl Balloon {
         t_cost: usize,
         step_delta: usize,
         prog: Progress,
-    ) -> Self {
-        #[cfg(debug_assertions)]
-        {
-            assert!(s_cost > 0, "s_cost must be positive");
-            assert!(t_cost > 0, "s_cost must be positive");
-            assert!(step_delta > 0, "step_delta must be positive");
+    ) -> Result<Self> {
+        Self::with_revision(
+            passwd,
+            salt,
+            s_cost,
+            t_cost,
+            step_delta,
+            Revision::legacy(),
+            prog,
+        )
+    }
+
+    /// Like `new` but uses the given `revision` of the algorithm.
+    pub fn with_revision(
+        passwd: impl AsRef<[u8]>,
+        salt: Vec<u8>,
+        s_cost: usize,
+        t_cost: usize,
+        step_delta: usize,
+        revision: Revision,
+        prog: Progress,
+    ) -> Result<Self> {
+        let variant = Variant {
+            revision,
+            indexing: Indexing::Independent,
+            p_cost: 1,
+        };
+        Self::with_variant(passwd, salt, s_cost, t_cost, step_delta, variant, prog)
+    }
+
+    /// Like `new` but uses the given `variant` of the algorithm.
+    pub fn with_variant(
+        passwd: impl AsRef<[u8]>,
+        salt: Vec<u8>,
+        s_cost: usize,
+        t_cost: usize,
+        step_delta: usize,
+        variant: Variant,
+        prog: Progress,
+    ) -> Result<Self> {
+        Self::with_hash(passwd, salt, s_cost, t_cost, step_delta, variant, prog)
+    }
+}
+
+impl<D: BalloonHash> Balloon<D> {
+    /// Like `with_variant` but uses the hash function `D`.
+    /// With several lanes `s_cost` is rounded down to a multiple of `p_cost`
+    /// and has to be at least `4 * p_cost`.
+    /// With the `mlock` feature the buffer is locked in memory if the OS allows it.
+    pub fn with_hash(
+        passwd: impl AsRef<[u8]>,
+        salt: Vec<u8>,
+        s_cost: usize,
+        t_cost: usize,
+        step_delta: usize,
+        variant: Variant,
+        prog: Progress,
+    ) -> Result<Self> {
+        for (parameter, value) in [
+            ("s_cost", s_cost),
+            ("t_cost", t_cost),
+            ("step_delta", step_delta),
+        ] {
+            if value == 0 {
+                return Err(Error::InvalidSettings(format!(
+                    "{parameter} must be positive"
+                )));
+            }
         }
+        let p_cost = variant.p_cost;
+        if p_cost == 0 || (p_cost > 1 && s_cost < SYNC_POINTS * p_cost) {
+            return Err(Error::InvalidSettings(format!(
+                "p_cost of {p_cost} does not fit s_cost of {s_cost}"
+            )));
+        }
+        let segments = if p_cost == 1 { 1 } else { SYNC_POINTS };
+        let s_cost = s_cost / p_cost * p_cost;
+
+        let mut buffer = Vec::new();
+        buffer.try_reserve_exact(s_cost)?;
+        buffer.resize(s_cost, [0_u8; 64]);
+        // `buffer` never reallocates, so it can be locked now.
+        let locked = lock(&buffer);
 
         let mut res = Self {
-            buffer: vec![[0_u8; 64]; s_cost],
-            hash: Sha3_512::new(),
+            buffer,
+            state: Lane::new(),
             salt,
             step_delta,
-            cnt: 0,
             pos: 0,
+            revision: variant.revision,
+            indexing: variant.indexing,
+            p_cost,
+            locked,
+        };
+        let params = Params {
+            salt: &res.salt,
+            step_delta,
+            variant,
         };
+        let passwd = passwd.as_ref();
+        let mut lanes: Vec<Lane<D>> = (0..p_cost).map(|_| Lane::new()).collect();
 
         prog.set_state("Filling buffer".to_string());
         prog.inc_max(s_cost * t_cost);
 
         // fill buffer
-        res.hash.update(Self::int_to_arr(res.cnt));
-        res.cnt += 1;
-        res.hash.update(&passwd);
-        res.hash.update(&res.salt);
-        res.buffer[0] = res.hash.finalize_fixed_reset().into();
-        for m in 1..s_cost {
-            res.hash.update(Self::int_to_arr(res.cnt));
-            res.cnt += 1;
-            res.hash.update(res.buffer[m - 1]);
-            res.buffer[m] = res.hash.finalize_fixed_reset().into();
+        for segment in 0..segments {
+            for_each_lane(
+                &mut res.buffer,
+                &mut lanes,
+                segment,
+                segments,
+                |lane, view| lane.fill(view, passwd, &params),
+            );
         }
 
         prog.set_state("Mixing buffer".to_string());
 
         // mix buffer t_cost times
         for _ in 0..t_cost {
-            for _ in 0..s_cost {
-                res.step_internal(prog.clone());
+            for segment in 0..segments {
+                for_each_lane(
+                    &mut res.buffer,
+                    &mut lanes,
+                    segment,
+                    segments,
+                    |lane, view| {
+                        for pos in view.positions() {
+                            lane.mix(view, pos, &params, &prog);
+                        }
+                    },
+                );
             }
         }
 
-        res
+        // Stepping continues on the whole buffer as a single lane
+        res.state.cnt = lanes.iter().map(|lane| lane.cnt).sum();
+        res.state.steps = lanes.iter().map(|lane| lane.steps).sum();
+        Ok(res)
     }
 
     /// Steps the internal state. Doing this `s_cost`-times equals one round of buffer mixing.
@@ -73,63 +572,128 @@ impl Balloon {
     /// # Returns
     /// * Buffer at current position after mixing (before incrementing position).
     fn step_internal(&mut self, prog: Progress) -> [u8; 64] {
-        prog.inc();
-
         let s_cost = self.buffer.len();
-
-        // instead of "self.buffer[(self.pos as i64 - 1).rem_euclid(s_cost as i64) as usize]"
-        let prev = if self.pos == 0 {
-            self.buffer[s_cost - 1]
-        } else {
-            self.buffer[self.pos]
+        let params = Params {
+            salt: &self.salt,
+            step_delta: self.step_delta,
+            variant: Variant {
+                revision: self.revision,
+                indexing: self.indexing,
+                p_cost: 1,
+            },
+        };
+        let mut view = View {
+            lane: 0,
+            start: 0,
+            current: &mut self.buffer,
+            outside: &[(&[], &[])],
         };
-        self.hash.update(prev);
-        self.hash.update(Self::int_to_arr(self.cnt));
-        self.cnt += 1;
-        self.hash.update(self.buffer[self.pos]);
-        self.buffer[self.pos] = self.hash.finalize_fixed_reset().into();
 
-        for i in 0..self.step_delta {
-            self.hash.update(Self::int_to_arr(i as u64));
-            self.hash.update(Self::int_to_arr(self.cnt));
-            self.cnt += 1;
-            self.hash.update(&self.salt);
+        let res = self.state.mix(&mut view, self.pos, &params, &prog);
+        self.pos = (self.pos + 1).rem_euclid(s_cost); // (pos + 1) % s_cost
+        res
+    }
 
-            // There must be a better way to do this
-            let tmp: [u8; 64] = self.hash.finalize_fixed_reset().into();
-            let mut tmp2: [u8; 8] = [0u8; 8];
-            tmp2.copy_from_slice(&tmp[0..8]);
+    /// Current state of the buffer.
+    pub(crate) fn buffer(&self) -> &[[u8; 64]] {
+        &self.buffer
+    }
+
+    /// Same as `step_internal` but uses an additional hash to decouple internal state from outside world.
+    pub fn step(&mut self, prog: Progress) -> [u8; 64] {
+        let res = self.step_internal(prog);
+        Digest::update(&mut self.state.hash, res);
+        self.state.hash.finalize_fixed_reset().into()
+    }
 
-            let other: usize = Self::arr_to_int(&tmp2).rem_euclid(s_cost as u64) as usize;
+    /// Computes block `index` of a keystream directly from the buffer without
+    /// changing it, so that blocks can be computed in any order.
+    /// Like `step` every block takes `step_delta` samples from the buffer.
+    pub fn block(&self, index: u64) -> [u8; 64] {
+        let revision = self.revision;
+        let decode = |bytes: &[u8]| revision.decode(bytes.try_into().unwrap());
+        let len = self.buffer.len() as u64;
+        let mut hash = D::new();
 
-            self.hash.update(Self::int_to_arr(self.cnt));
-            self.cnt += 1;
-            self.hash.update(self.buffer[self.pos]);
-            self.hash.update(self.buffer[other]);
+        Digest::update(&mut hash, revision.encode(index));
+        Digest::update(&mut hash, self.buffer[(index % len) as usize]);
+        let mut res: [u8; 64] = hash.finalize_fixed_reset().into();
+
+        for i in 0..self.step_delta {
+            Digest::update(&mut hash, revision.encode(index));
+            Digest::update(&mut hash, revision.encode(i as u64));
+            Digest::update(&mut hash, &self.salt);
+            let tmp: [u8; 64] = hash.finalize_fixed_reset().into();
+            let other = (decode(&tmp[0..8]) % len) as usize;
 
-            self.buffer[self.pos] = self.hash.finalize_fixed_reset().into();
+            Digest::update(&mut hash, res);
+            Digest::update(&mut hash, self.buffer[other]);
+            res = hash.finalize_fixed_reset().into();
         }
+        res
+    }
+}
 
-        let res = self.buffer[self.pos];
-        self.pos = (self.pos + 1).rem_euclid(s_cost); // (pos + 1) % s_cost
+/// KMAC256 as defined in NIST SP 800-185 with a fixed output length of 512 bits.
+#[derive(Clone)]
+pub struct Kmac256 {
+    inner: CShake256,
+}
+
+impl Kmac256 {
+    /// Rate of `cSHAKE256` in bytes.
+    const RATE: usize = 136;
+    const OUTPUT_BITS: u64 = 512;
+
+    /// Create a new instance keyed with `key` and using the customization string `custom`.
+    pub fn new(key: &[u8], custom: &[u8]) -> Self {
+        let mut inner =
+            CShake256::from_core(CShake256Core::new_with_function_name(b"KMAC", custom));
+
+        // bytepad(encode_string(key), RATE)
+        let mut prefix = Self::left_encode(Self::RATE as u64);
+        prefix.extend(Self::left_encode(key.len() as u64 * 8));
+        // reserved upfront, so no copies of the key are left behind by reallocation
+        let len = (prefix.len() + key.len()).next_multiple_of(Self::RATE);
+        let mut padded = Zeroizing::new(Vec::with_capacity(len));
+        padded.extend_from_slice(&prefix);
+        padded.extend_from_slice(key);
+        padded.resize(len, 0);
+        digest::Update::update(&mut inner, &padded);
+
+        Self { inner }
+    }
+
+    pub fn update(&mut self, data: impl AsRef<[u8]>) {
+        digest::Update::update(&mut self.inner, data.as_ref());
+    }
+
+    pub fn finalize(mut self) -> [u8; 64] {
+        digest::Update::update(&mut self.inner, &Self::right_encode(Self::OUTPUT_BITS));
+        let mut res = [0_u8; 64];
+        self.inner.finalize_xof().read(&mut res);
         res
     }
 
-    /// Same as `step_internal` but uses an additional hash to decouple internal state from outside world.
-    pub fn step(&mut self, prog: Progress) -> [u8; 64] {
-        let res = self.step_internal(prog);
-        self.hash.update(res);
-        self.hash.finalize_fixed_reset().into()
+    /// Computes the KMAC of `data` in one go.
+    pub fn mac(key: &[u8], data: impl AsRef<[u8]>, custom: &[u8]) -> [u8; 64] {
+        let mut kmac = Self::new(key, custom);
+        kmac.update(data);
+        kmac.finalize()
     }
 
-    #[inline]
-    fn int_to_arr(val: u64) -> [u8; 8] {
-        // unsafe { std::mem::transmute::<u64, [u8; 8]>(val) }
-        val.to_ne_bytes()
+    fn left_encode(val: u64) -> Vec<u8> {
+        let bytes = val.to_be_bytes();
+        let skip = bytes.iter().take(7).take_while(|b| **b == 0).count();
+        let mut res = vec![(8 - skip) as u8];
+        res.extend_from_slice(&bytes[skip..]);
+        res
     }
-    #[inline]
-    fn arr_to_int(arr: &[u8; 8]) -> u64 {
-        unsafe { std::mem::transmute::<[u8; 8], u64>(*arr) }
+
+    fn right_encode(val: u64) -> Vec<u8> {
+        let mut res = Self::left_encode(val);
+        res.rotate_left(1);
+        res
     }
 }
 
@@ -137,7 +701,7 @@ impl Balloon {
 mod tests {
     use crate::progress::Progress;
 
-    use super::Balloon;
+    use super::{Balloon, BalloonHash, Indexing, Kmac256, Revision, Variant};
     use digest::FixedOutputReset;
     use hex_literal::hex;
     use sha3::{Digest, Sha3_512};
@@ -171,7 +735,7 @@ mod tests {
 
     #[test]
     fn index_test() {
-        let vec = vec![1, 2, 3, 4, 5];
+        let vec = [1, 2, 3, 4, 5];
         assert_eq!(vec[(-1i32).rem_euclid(vec.len() as i32) as usize], 5)
     }
     #[test]
@@ -185,10 +749,227 @@ mod tests {
             for k in 1..5 {
                 for j in 1..5 {
                     let salt = vec![1, 2, 3];
-                    let b = Balloon::new("password", salt, i, j, k, Progress::new());
-                    assert_eq!(b.cnt as usize, i + i * j * (k * 2 + 1));
+                    let b = Balloon::new("password", salt, i, j, k, Progress::new()).unwrap();
+                    assert_eq!(b.state.cnt as usize, i + i * j * (k * 2 + 1));
                 }
             }
         }
     }
+
+    #[test]
+    fn debug_hides_state() {
+        let b = Balloon::new("password", vec![1, 2, 3], 4, 1, 1, Progress::new()).unwrap();
+        let debug = format!("{b:?}");
+        assert_eq!(
+            debug,
+            "Balloon { s_cost: 4, step_delta: 1, revision: V0, indexing: Independent, p_cost: 1, .. }"
+        );
+    }
+
+    #[test]
+    fn allocation_failure_is_error() {
+        let res = Balloon::new("password", vec![1, 2, 3], usize::MAX, 1, 1, Progress::new());
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn zero_costs_are_errors() {
+        for (s_cost, t_cost, step_delta) in [(0, 1, 1), (4, 0, 1), (4, 1, 0)] {
+            let res = Balloon::new(
+                "password",
+                vec![1],
+                s_cost,
+                t_cost,
+                step_delta,
+                Progress::new(),
+            );
+            assert!(matches!(res, Err(crate::Error::InvalidSettings(_))));
+        }
+    }
+
+    /// Output of the first step of a small Balloon when integers are encoded in
+    /// little endian byte order: with `Revision::V1` on every host and with
+    /// `Revision::V0` on little endian hosts.
+    const KAT_LITTLE_ENDIAN: [u8; 64] = hex!(
+        "EE C1 32 0C 82 B5 2C 64 27 9B FF 10 64 37 2F 9F
+         F0 8D 18 73 08 67 81 88 39 2F B1 4B 33 41 4A C4
+         0D 1D 69 C1 11 A4 84 45 83 59 E2 11 9A 19 71 5F
+         A1 BF 6B F1 F0 49 0C 58 E9 C4 75 DD 19 56 2E C0"
+    );
+    /// Output of the same Balloon using `Revision::V0` on big endian hosts.
+    const KAT_BIG_ENDIAN: [u8; 64] = hex!(
+        "21 DB EB 38 5D 03 22 08 82 39 7F 34 A3 61 22 F8
+         2A 13 4C FB 2E F9 2E 53 97 A5 94 CD 1A E1 AA B5
+         C6 00 7A 15 32 A0 5B ED 75 71 70 BD 75 94 B9 2B
+         BC 35 8E 88 36 C4 14 6B A1 FD 57 DD 58 0D 8F 0F"
+    );
+
+    fn first_step(revision: Revision) -> [u8; 64] {
+        let salt = vec![1, 2, 3];
+        let mut b =
+            Balloon::with_revision("password", salt, 16, 2, 3, revision, Progress::new()).unwrap();
+        b.step(Progress::new())
+    }
+
+    /// `Revision::V1` has to produce the same output on every host.
+    #[test]
+    fn balloon_is_portable() {
+        assert_eq!(first_step(Revision::V1), KAT_LITTLE_ENDIAN);
+    }
+
+    /// Output of the same Balloon using `Revision::V2`.
+    const KAT_V2: [u8; 64] = hex!(
+        "B9 B1 94 FF 48 EB B4 DA E6 0B 62 0B E2 9C 1A A1
+         21 03 56 47 E5 85 3D 04 58 E0 0F D0 B1 D7 3C 5F
+         2C 70 08 37 9C 9C 5C 64 BC 8E 32 D7 D9 24 A5 FF
+         D4 6C A3 8B 41 D5 F8 A5 CF 06 C8 A6 05 44 B6 8F"
+    );
+
+    #[test]
+    fn balloon_v2_known_answer() {
+        assert_eq!(first_step(Revision::V2), KAT_V2);
+        assert_ne!(first_step(Revision::V2), first_step(Revision::V1));
+    }
+
+    /// `Revision::V0` depends on the byte order of the host.
+    #[test]
+    fn legacy_balloon_uses_native_endian() {
+        let expected = if cfg!(target_endian = "little") {
+            KAT_LITTLE_ENDIAN
+        } else {
+            KAT_BIG_ENDIAN
+        };
+        assert_eq!(first_step(Revision::V0), expected);
+        let mut b = Balloon::new("password", vec![1, 2, 3], 16, 2, 3, Progress::new()).unwrap();
+        assert_eq!(b.step(Progress::new()), expected);
+    }
+
+    fn first_step_with(indexing: Indexing) -> [u8; 64] {
+        let variant = Variant {
+            revision: Revision::V2,
+            indexing,
+            p_cost: 1,
+        };
+        let mut b = Balloon::with_variant(
+            "password",
+            vec![1, 2, 3],
+            16,
+            2,
+            3,
+            variant,
+            Progress::new(),
+        )
+        .unwrap();
+        b.step(Progress::new())
+    }
+
+    #[test]
+    fn indexing_modes_differ() {
+        let independent = first_step_with(Indexing::Independent);
+        let dependent = first_step_with(Indexing::Dependent);
+        let hybrid = first_step_with(Indexing::Hybrid);
+        assert_eq!(independent, KAT_V2);
+        assert_ne!(dependent, independent);
+        assert_ne!(hybrid, independent);
+        assert_ne!(hybrid, dependent);
+    }
+
+    fn first_step_with_lanes(p_cost: usize, s_cost: usize) -> Result<[u8; 64], crate::Error> {
+        let variant = Variant {
+            p_cost,
+            ..Variant::default()
+        };
+        let mut b = Balloon::with_variant(
+            "password",
+            vec![1, 2, 3],
+            s_cost,
+            2,
+            3,
+            variant,
+            Progress::new(),
+        )?;
+        assert_eq!(b.buffer().len(), s_cost / p_cost * p_cost);
+        assert_eq!(b.state.cnt as usize, b.buffer().len() * (1 + 2 * 7));
+        Ok(b.step(Progress::new()))
+    }
+
+    #[test]
+    fn lanes() {
+        assert_eq!(first_step_with_lanes(1, 16).unwrap(), KAT_V2);
+        let two = first_step_with_lanes(2, 16).unwrap();
+        assert_ne!(two, KAT_V2);
+        assert_eq!(two, first_step_with_lanes(2, 16).unwrap());
+        assert_ne!(two, first_step_with_lanes(4, 16).unwrap());
+        // Rounded down to a multiple of `p_cost`
+        assert_eq!(
+            first_step_with_lanes(3, 17).unwrap(),
+            first_step_with_lanes(3, 17).unwrap()
+        );
+        assert_ne!(first_step_with_lanes(8, 1000).unwrap(), [0; 64]);
+
+        assert!(first_step_with_lanes(0, 16).is_err());
+        assert!(first_step_with_lanes(5, 16).is_err());
+    }
+
+    fn first_step_with_hash<D: BalloonHash>() -> [u8; 64] {
+        let mut b = Balloon::<D>::with_hash(
+            "password",
+            vec![1, 2, 3],
+            16,
+            2,
+            3,
+            Variant::default(),
+            Progress::new(),
+        )
+        .unwrap();
+        b.step(Progress::new())
+    }
+
+    #[test]
+    fn hash_functions_differ() {
+        let sha3 = first_step_with_hash::<Sha3_512>();
+        let blake2 = first_step_with_hash::<blake2::Blake2b512>();
+        let sha2 = first_step_with_hash::<sha2::Sha512>();
+        assert_eq!(sha3, KAT_V2);
+        assert_ne!(blake2, sha3);
+        assert_ne!(sha2, sha3);
+        assert_ne!(sha2, blake2);
+        assert_eq!(blake2, first_step_with_hash::<blake2::Blake2b512>());
+    }
+
+    #[test]
+    fn blocks_in_any_order() {
+        let b = Balloon::new("password", vec![1, 2, 3], 16, 2, 3, Progress::new()).unwrap();
+        let buffer = b.buffer().to_vec();
+        let blocks: Vec<[u8; 64]> = (0..40).map(|i| b.block(i)).collect();
+        assert_eq!(b.buffer(), &buffer[..]);
+        assert_eq!(b.block(17), blocks[17]);
+        assert_eq!(b.block(3), blocks[3]);
+        assert_ne!(blocks[0], blocks[16]);
+        assert_ne!(blocks[1], blocks[2]);
+    }
+
+    #[test]
+    fn revision_encoding() {
+        let val = 0x0102_0304_0506_0708;
+        assert_eq!(Revision::V1.encode(val), [8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(Revision::V1.decode([8, 7, 6, 5, 4, 3, 2, 1]), val);
+        assert_eq!(Revision::V0.encode(val), val.to_ne_bytes());
+    }
+
+    #[test]
+    fn kmac_impl_test() {
+        // Sample #4 taken from NIST
+        let key: Vec<u8> = (0x40..0x60).collect();
+        let res = Kmac256::mac(&key, [0, 1, 2, 3], b"My Tagged Application");
+        assert_eq!(
+            res,
+            hex!(
+                "20 C5 70 C3 13 46 F7 03 C9 AC 36 C6 1C 03 CB 64
+                 C3 97 0D 0C FC 78 7E 9B 79 59 9D 27 3A 68 D2 F7
+                 F6 9D 4C C3 DE 9D 10 4A 35 16 89 F2 7C F6 F5 95
+                 1F 01 03 F3 3F 4F 24 87 10 24 D9 C2 77 73 A8 DD"
+            )
+        );
+    }
 }
@@ -0,0 +1,413 @@
+//! This module contains the key derivation functions that turn a password into a key.
+//! All of them implement the `Kdf` trait, `KdfSettings` selects one of them.
+
+use crate::cipher::to_usize;
+use crate::error::{Error, Result};
+use crate::hash::{Balloon, Kmac256, Revision};
+use crate::progress::Progress;
+
+use serde::{Deserialize, Serialize};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+/// Secret mixed into the key derivation. Unlike the salt it is never stored,
+/// so it has to be provided again for decryption, see `secret::Peppered`.
+/// Wiped when dropped.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Pepper([u8; 32]);
+
+impl Pepper {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl Zeroize for Pepper {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Drop for Pepper {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl ZeroizeOnDrop for Pepper {}
+
+impl std::fmt::Debug for Pepper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Pepper(..)")
+    }
+}
+
+/// Mixes `pepper` into `password`, for functions without native support.
+fn with_pepper(password: &[u8], pepper: Option<&Pepper>) -> Zeroizing<Vec<u8>> {
+    match pepper {
+        Some(pepper) => {
+            let mixed = Zeroizing::new(Kmac256::mac(&pepper.0, password, b"zeppelin pepper"));
+            Zeroizing::new(mixed.to_vec())
+        }
+        None => Zeroizing::new(password.to_vec()),
+    }
+}
+
+/// Function deriving a 64 byte key from a password and a salt.
+pub trait Kdf {
+    /// Derives the key, mixing in `pepper` if it is provided.
+    /// The key is wiped when dropped.
+    fn derive(
+        &self,
+        password: &[u8],
+        salt: &[u8],
+        pepper: Option<&Pepper>,
+        prog: Progress,
+    ) -> Result<Zeroizing<[u8; 64]>>;
+}
+
+/// Parameters of the Argon2id key derivation.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct Argon2Settings {
+    /// Memory size in KiB.
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl std::default::Default for Argon2Settings {
+    /// Default settings with `m_cost` ~ 19 MB
+    fn default() -> Self {
+        Self {
+            m_cost: 19 * 1024,
+            t_cost: 20,
+            p_cost: 1,
+        }
+    }
+}
+
+impl Kdf for Argon2Settings {
+    /// Uses the pepper as the secret of Argon2.
+    fn derive(
+        &self,
+        password: &[u8],
+        salt: &[u8],
+        pepper: Option<&Pepper>,
+        _prog: Progress,
+    ) -> Result<Zeroizing<[u8; 64]>> {
+        #[inline]
+        fn inner(
+            settings: &Argon2Settings,
+            password: &[u8],
+            salt: &[u8],
+            pepper: &[u8],
+        ) -> argon2::Result<Zeroizing<[u8; 64]>> {
+            use argon2::{Algorithm, Argon2, Params, Version};
+
+            let mut output = Zeroizing::new([0u8; 64]);
+            let params = Params::new(settings.m_cost, settings.t_cost, settings.p_cost, None)?;
+            let argon2 =
+                Argon2::new_with_secret(pepper, Algorithm::Argon2id, Version::V0x13, params)?;
+            argon2.hash_password_into(password, salt, &mut *output)?;
+            Ok(output)
+        }
+
+        let pepper = match pepper {
+            Some(pepper) => &pepper.0[..],
+            None => &[],
+        };
+        match inner(self, password, salt, pepper) {
+            Ok(inner) => Ok(inner),
+            Err(e) => Err(Error::KeyDerivation(e.to_string())),
+        }
+    }
+}
+
+/// Parameters of the scrypt key derivation.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct ScryptSettings {
+    /// Logarithm of the number of blocks.
+    pub log_n: u8,
+    /// Block size in 128 byte chunks.
+    pub r: u32,
+    pub p: u32,
+}
+
+impl ScryptSettings {
+    /// Size of the memory required in bytes.
+    pub fn memory(&self) -> u64 {
+        match 1_u64.checked_shl(self.log_n as u32) {
+            Some(n) => n.saturating_mul(128).saturating_mul(self.r as u64),
+            None => u64::MAX,
+        }
+    }
+}
+
+impl std::default::Default for ScryptSettings {
+    /// Default settings with ~ 128 MiB of memory
+    fn default() -> Self {
+        Self {
+            log_n: 17,
+            r: 8,
+            p: 1,
+        }
+    }
+}
+
+impl Kdf for ScryptSettings {
+    fn derive(
+        &self,
+        password: &[u8],
+        salt: &[u8],
+        pepper: Option<&Pepper>,
+        _prog: Progress,
+    ) -> Result<Zeroizing<[u8; 64]>> {
+        let password = with_pepper(password, pepper);
+
+        let params = match scrypt::Params::new(self.log_n, self.r, self.p, 64) {
+            Ok(params) => params,
+            Err(e) => return Err(Error::KeyDerivation(e.to_string())),
+        };
+        let mut output = Zeroizing::new([0u8; 64]);
+        match scrypt::scrypt(&password, salt, &params, &mut *output) {
+            Ok(()) => Ok(output),
+            Err(e) => Err(Error::KeyDerivation(e.to_string())),
+        }
+    }
+}
+
+/// Parameters of the PBKDF2-HMAC-SHA512 key derivation.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct Pbkdf2Settings {
+    pub rounds: u32,
+}
+
+impl std::default::Default for Pbkdf2Settings {
+    /// Default settings as recommended by OWASP
+    fn default() -> Self {
+        Self { rounds: 210_000 }
+    }
+}
+
+impl Kdf for Pbkdf2Settings {
+    fn derive(
+        &self,
+        password: &[u8],
+        salt: &[u8],
+        pepper: Option<&Pepper>,
+        _prog: Progress,
+    ) -> Result<Zeroizing<[u8; 64]>> {
+        let password = with_pepper(password, pepper);
+
+        if self.rounds == 0 {
+            return Err(Error::KeyDerivation("rounds must be positive".to_string()));
+        }
+        let mut output = Zeroizing::new([0u8; 64]);
+        pbkdf2::pbkdf2_hmac::<sha2::Sha512>(&password, salt, self.rounds, &mut *output);
+        Ok(output)
+    }
+}
+
+/// Parameters of the key derivation based on `hash::Balloon` alone.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct BalloonSettings {
+    pub s_cost: u64,
+    pub t_cost: u64,
+    pub step_delta: u64,
+    #[serde(default = "Revision::legacy")]
+    pub revision: Revision,
+}
+
+impl std::default::Default for BalloonSettings {
+    /// Default settings with `s_cost` ~ 16 MB
+    fn default() -> Self {
+        Self {
+            s_cost: 1 << 18,
+            t_cost: 3,
+            step_delta: 3,
+            revision: Revision::latest(),
+        }
+    }
+}
+
+impl Kdf for BalloonSettings {
+    fn derive(
+        &self,
+        password: &[u8],
+        salt: &[u8],
+        pepper: Option<&Pepper>,
+        prog: Progress,
+    ) -> Result<Zeroizing<[u8; 64]>> {
+        let password = with_pepper(password, pepper);
+
+        if self.s_cost == 0 || self.t_cost == 0 || self.step_delta == 0 {
+            return Err(Error::KeyDerivation(
+                "Balloon parameters must be positive".to_string(),
+            ));
+        }
+        let mut balloon = Balloon::with_revision(
+            &password,
+            salt.to_vec(),
+            to_usize(self.s_cost, "s_cost")?,
+            to_usize(self.t_cost, "t_cost")?,
+            to_usize(self.step_delta, "step_delta")?,
+            self.revision,
+            prog.clone(),
+        )?;
+        Ok(Zeroizing::new(balloon.step(prog)))
+    }
+}
+
+/// Key derivation function and its parameters, recorded in every container.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+#[serde(tag = "algorithm")]
+pub enum KdfSettings {
+    Argon2id(Argon2Settings),
+    Scrypt(ScryptSettings),
+    Pbkdf2Sha512(Pbkdf2Settings),
+    /// Relies only on the memory-hard `hash::Balloon`.
+    Balloon(BalloonSettings),
+}
+
+impl KdfSettings {
+    /// Key derivation used before it was recorded. Spelled out instead of
+    /// taking `Argon2Settings::default`, so changing the defaults keeps old
+    /// containers readable.
+    pub(crate) fn legacy() -> Self {
+        Self::Argon2id(Argon2Settings {
+            m_cost: 19456,
+            t_cost: 20,
+            p_cost: 1,
+        })
+    }
+}
+
+impl std::default::Default for KdfSettings {
+    fn default() -> Self {
+        Self::Argon2id(Argon2Settings::default())
+    }
+}
+
+impl Kdf for KdfSettings {
+    fn derive(
+        &self,
+        password: &[u8],
+        salt: &[u8],
+        pepper: Option<&Pepper>,
+        prog: Progress,
+    ) -> Result<Zeroizing<[u8; 64]>> {
+        match self {
+            Self::Argon2id(settings) => settings.derive(password, salt, pepper, prog),
+            Self::Scrypt(settings) => settings.derive(password, salt, pepper, prog),
+            Self::Pbkdf2Sha512(settings) => settings.derive(password, salt, pepper, prog),
+            Self::Balloon(settings) => settings.derive(password, salt, pepper, prog),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn small_settings() -> [KdfSettings; 4] {
+        [
+            KdfSettings::Argon2id(Argon2Settings {
+                m_cost: 256,
+                t_cost: 2,
+                p_cost: 1,
+            }),
+            KdfSettings::Scrypt(ScryptSettings {
+                log_n: 8,
+                r: 8,
+                p: 1,
+            }),
+            KdfSettings::Pbkdf2Sha512(Pbkdf2Settings { rounds: 1000 }),
+            KdfSettings::Balloon(BalloonSettings {
+                s_cost: 1000,
+                t_cost: 2,
+                step_delta: 3,
+                revision: Revision::latest(),
+            }),
+        ]
+    }
+
+    #[test]
+    fn kdfs_are_deterministic_and_differ() {
+        let mut keys = Vec::new();
+        for kdf in small_settings() {
+            let key = kdf.derive(b"password", b"some salt", None, Progress::new());
+            let key = key.unwrap();
+            assert_eq!(
+                key,
+                kdf.derive(b"password", b"some salt", None, Progress::new())
+                    .unwrap()
+            );
+            assert_ne!(
+                key,
+                kdf.derive(b"password", b"other salt", None, Progress::new())
+                    .unwrap()
+            );
+            assert_ne!(
+                key,
+                kdf.derive(
+                    b"password",
+                    b"some salt",
+                    Some(&Pepper::new([1; 32])),
+                    Progress::new()
+                )
+                .unwrap()
+            );
+            keys.push(key);
+        }
+        keys.dedup();
+        assert_eq!(keys.len(), 4);
+    }
+
+    #[test]
+    fn pbkdf2_impl_test() {
+        use hex_literal::hex;
+
+        let kdf = Pbkdf2Settings { rounds: 1 };
+        let key = kdf
+            .derive(b"password", b"salt", None, Progress::new())
+            .unwrap();
+        assert_eq!(
+            *key,
+            hex!(
+                "86 7f 70 cf 1a de 02 cf f3 75 25 99 a3 a5 3d c4
+                 af 34 c7 a6 69 81 5a e5 d5 13 55 4e 1c 8c f2 52
+                 c0 2d 47 0a 28 5a 05 01 ba d9 99 bf e9 43 c0 8f
+                 05 02 35 d7 d6 8b 1d a5 5e 63 f7 3b 60 a5 7f ce"
+            )
+        );
+    }
+
+    #[test]
+    fn settings_serialize_with_algorithm() {
+        for kdf in small_settings() {
+            let serial = serde_json::to_string(&kdf).unwrap();
+            assert!(serial.starts_with(r#"{"algorithm":"#));
+            assert_eq!(kdf, serde_json::from_str(&serial).unwrap());
+        }
+    }
+
+    #[test]
+    fn legacy_settings_are_fixed() {
+        let expected = KdfSettings::Argon2id(Argon2Settings {
+            m_cost: 19456,
+            t_cost: 20,
+            p_cost: 1,
+        });
+        assert_eq!(KdfSettings::legacy(), expected);
+    }
+
+    #[test]
+    fn invalid_parameters_are_errors() {
+        let kdf = KdfSettings::Scrypt(ScryptSettings {
+            log_n: 8,
+            r: 0,
+            p: 1,
+        });
+        let res = kdf.derive(b"password", b"some salt", None, Progress::new());
+        assert!(matches!(res, Err(Error::KeyDerivation(_))));
+    }
+}
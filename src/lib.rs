@@ -15,6 +15,7 @@
 //! ## Non-cryptographic features
 //! - flexible container format that can be extended
 //! - can be used on anything that implements the `Read` and `Seek` traits
+//! - a single container can hold a whole directory tree
 //!
 //! ## Examples
 //! This example shows how to use the high-level API based on the `Read` and `Write` traits.
@@ -39,7 +40,13 @@
 //!
 //! let mut res = Cursor::new(Vec::<u8>::new());
 //!
-//! container::read_container(&mut tmp, &mut res, "Secret password", None).unwrap();
+//! container::read_container(
+//!     &mut tmp,
+//!     &mut res,
+//!     "Secret password",
+//!     container::DecryptPolicy::default(),
+//!     None,
+//! ).unwrap();
 //!
 //! assert_eq!(source, res)
 //! ```
@@ -50,8 +57,14 @@
 pub mod cipher;
 // mod files;
 pub mod container;
+pub mod error;
 pub mod hash;
+pub mod kdf;
 pub mod progress;
+pub mod secret;
+pub mod test_vectors;
+
+pub use error::{Error, Result};
 
-#[cfg(feature = "1password")]
+#[cfg(feature = "one_password")]
 pub mod op;
@@ -2,13 +2,15 @@
 //! the status of an ongoing operation between threads.
 
 use std::{
-    io,
+    io::{self, Read},
     sync::{Arc, Mutex},
 };
 
 struct ProgressState {
     progress: usize,
     out_of: usize,
+    bytes: u64,
+    length_known: bool,
     //last_call: time::Instant,
     //delta: time::Duration,
     state: String,
@@ -26,6 +28,8 @@ impl Progress {
             inner: Arc::new(Mutex::new(ProgressState {
                 progress: 0,
                 out_of: 1,
+                bytes: 0,
+                length_known: true,
                 //last_call: time::Instant::now(),
                 //delta: time::Duration::from_millis(0),
                 state: String::new(),
@@ -53,6 +57,24 @@ impl Progress {
     pub fn set_max_data(&self, bytes: usize) {
         self.set_max(bytes / 64)
     }
+    /// The amount of data is unknown, e.g. because it is read from a pipe.
+    /// `percentage` then only covers the creation of the cipher and progress
+    /// should be reported in bytes using `get_bytes`.
+    pub fn set_length_unknown(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.length_known = false;
+    }
+    pub fn is_length_known(&self) -> bool {
+        self.inner.lock().unwrap().length_known
+    }
+    pub fn inc_bytes(&self, bytes: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.bytes += bytes;
+    }
+    /// Number of bytes of data processed so far.
+    pub fn get_bytes(&self) -> u64 {
+        self.inner.lock().unwrap().bytes
+    }
     pub fn inc_max(&self, inc: usize) {
         let mut inner = self.inner.lock().unwrap();
         inner.out_of += inc;
@@ -74,6 +96,20 @@ impl Default for Progress {
     }
 }
 
+/// Reader counting the bytes read from `inner` in `Progress::get_bytes`.
+pub(crate) struct ProgressReader<R> {
+    pub(crate) inner: R,
+    pub(crate) prog: Progress,
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.prog.inc_bytes(n as u64);
+        Ok(n)
+    }
+}
+
 #[cfg(feature = "console")]
 pub fn print_progress_bar(
     out: &mut console::Term,
@@ -90,7 +126,7 @@ pub fn print_progress_bar(
 
     out.clear_line()?;
 
-    if w > 50 {
+    if w > 50 && prog.is_length_known() {
         out.write_all(b" ")?;
 
         let max = 30;
@@ -103,8 +139,12 @@ pub fn print_progress_bar(
         }
     }
     out.write_all(b" ")?;
-    out.write_all(format!("{percentage:>3.0}% ").as_bytes())?;
-    out.write_all(format!("[{count}/{max}]").as_bytes())?;
+    if prog.is_length_known() {
+        out.write_all(format!("{percentage:>3.0}% ").as_bytes())?;
+        out.write_all(format!("[{count}/{max}]").as_bytes())?;
+    } else {
+        out.write_all(format!("[{} bytes]", prog.get_bytes()).as_bytes())?;
+    }
     out.write_all(b" - ")?;
     out.write_all(state.as_bytes())?;
 
@@ -0,0 +1,187 @@
+//! This module contains `SecretBytes`, which is used to pass passwords and keys
+//! through the public API without leaving copies of them behind, and the `Key`
+//! trait, which lets a `Pepper` travel by reference together with the password.
+
+use crate::kdf::Pepper;
+
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+/// Secret byte string, e.g. a password or a key, that is wiped when dropped.
+/// Deliberately does not implement `Debug` or `Display`, so it can not be logged by accident.
+///
+/// It implements `AsRef<[u8]>` and can therefore be used everywhere a key is expected.
+#[derive(Clone)]
+pub struct SecretBytes(Zeroizing<Vec<u8>>);
+
+/// Password passed to `cipher` and `container`.
+pub type Password = SecretBytes;
+
+impl SecretBytes {
+    /// Takes ownership of `bytes`, they are wiped once this is dropped.
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Self(Zeroizing::new(bytes))
+    }
+
+    /// Exposes the secret, the result should not outlive `self`.
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl PartialEq for SecretBytes {
+    /// Compares in constant time, only the length may leak.
+    fn eq(&self, other: &Self) -> bool {
+        self.expose().ct_eq(other.expose()).into()
+    }
+}
+
+impl Eq for SecretBytes {}
+
+impl AsRef<[u8]> for SecretBytes {
+    fn as_ref(&self) -> &[u8] {
+        self.expose()
+    }
+}
+
+impl From<Vec<u8>> for SecretBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::new(bytes)
+    }
+}
+
+impl From<String> for SecretBytes {
+    fn from(string: String) -> Self {
+        Self::new(string.into_bytes())
+    }
+}
+
+impl From<&[u8]> for SecretBytes {
+    fn from(bytes: &[u8]) -> Self {
+        Self::new(bytes.to_vec())
+    }
+}
+
+impl From<&str> for SecretBytes {
+    fn from(string: &str) -> Self {
+        Self::new(string.as_bytes().to_vec())
+    }
+}
+
+/// Password or key passed to `cipher` and `container`, together with the `Pepper`
+/// required by `CryptSettings::peppered`, if any.
+///
+/// Everything that implements `AsRef<[u8]>` is a key without a pepper, `Peppered` adds one.
+pub trait Key {
+    /// The password or key itself.
+    fn secret(&self) -> &[u8];
+
+    /// The pepper to derive the key with.
+    fn pepper(&self) -> Option<&Pepper> {
+        None
+    }
+}
+
+impl<T: AsRef<[u8]> + ?Sized> Key for T {
+    fn secret(&self) -> &[u8] {
+        self.as_ref()
+    }
+}
+
+/// Key together with a borrowed `Pepper`, so the pepper is never copied.
+#[derive(Clone, Copy)]
+pub struct Peppered<'a, K> {
+    key: K,
+    pepper: Option<&'a Pepper>,
+}
+
+impl<'a, K: AsRef<[u8]>> Peppered<'a, K> {
+    pub fn new(key: K, pepper: &'a Pepper) -> Self {
+        Self {
+            key,
+            pepper: Some(pepper),
+        }
+    }
+
+    /// Like `new` but the pepper is optional.
+    pub(crate) fn with(key: K, pepper: Option<&'a Pepper>) -> Self {
+        Self { key, pepper }
+    }
+}
+
+impl<K: AsRef<[u8]>> Key for Peppered<'_, K> {
+    fn secret(&self) -> &[u8] {
+        self.key.as_ref()
+    }
+
+    fn pepper(&self) -> Option<&Pepper> {
+        self.pepper
+    }
+}
+
+impl<K: AsRef<[u8]>> Key for &Peppered<'_, K> {
+    fn secret(&self) -> &[u8] {
+        self.key.as_ref()
+    }
+
+    fn pepper(&self) -> Option<&Pepper> {
+        self.pepper
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cipher::{decrypt, encrypt, CryptSettings};
+    use crate::progress::Progress;
+    use std::io::Cursor;
+
+    #[test]
+    fn conversions_agree() {
+        let password = Password::from("password");
+        assert!(password == SecretBytes::from(b"password".to_vec()));
+        assert!(password == SecretBytes::from(String::from("password")));
+        assert!(password != SecretBytes::from("Password"));
+        assert_eq!(password.expose(), b"password");
+        assert_eq!(password.len(), 8);
+        assert!(SecretBytes::new(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn usable_as_key() {
+        let settings = CryptSettings::default_for_testing();
+        let password = Password::from("password");
+        let mut source = Cursor::new(b"Secret message".to_vec());
+        let mut ciphertext = Cursor::new(Vec::new());
+        let salt = encrypt(
+            &mut source,
+            &mut ciphertext,
+            &password,
+            b"",
+            settings,
+            Progress::new(),
+        )
+        .unwrap();
+
+        let mut res = Vec::new();
+        decrypt(
+            &mut ciphertext,
+            &mut res,
+            "password",
+            &salt,
+            b"",
+            settings,
+            Progress::new(),
+        )
+        .unwrap();
+        assert_eq!(res, b"Secret message");
+    }
+}
@@ -0,0 +1,588 @@
+//! This module generates and verifies known-answer test vectors for `hash::Balloon`,
+//! `cipher::Stream`, `cipher::encrypt` and containers.
+//! The corpus is stored as JSON, so that other implementations can prove
+//! byte-for-byte compatibility. All vectors use fixed salts.
+//!
+//! `hash::Revision::V0` depends on the byte order of the host and is not covered.
+
+use std::io::Cursor;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::cipher::{self, CryptSettings, Format, KeystreamMode};
+use crate::container::{self, DecryptPolicy};
+use crate::error::{Error, Result};
+use crate::hash::{Balloon, BalloonHash, HashFunction, Indexing, Revision, Variant};
+use crate::progress::Progress;
+
+/// Bytes serialized as lowercase hex string.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Hex(pub Vec<u8>);
+
+impl Serialize for Hex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let hex: String = self.0.iter().map(|b| format!("{b:02x}")).collect();
+        serializer.serialize_str(&hex)
+    }
+}
+
+impl<'de> Deserialize<'de> for Hex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        if hex.len() % 2 != 0 {
+            return Err(de::Error::custom("hex string of odd length"));
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2).unwrap_or("invalid"), 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .map(Hex)
+            .map_err(de::Error::custom)
+    }
+}
+
+impl From<&[u8]> for Hex {
+    fn from(bytes: &[u8]) -> Self {
+        Hex(bytes.to_vec())
+    }
+}
+
+/// State of a `Balloon` after creation and its first outputs.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct BalloonVector {
+    pub password: Hex,
+    pub salt: Hex,
+    pub s_cost: u64,
+    pub t_cost: u64,
+    pub step_delta: u64,
+    pub revision: Revision,
+    #[serde(default = "Indexing::legacy")]
+    pub indexing: Indexing,
+    #[serde(default = "cipher::single_lane")]
+    pub p_cost: u64,
+    #[serde(default = "HashFunction::legacy")]
+    pub hash: HashFunction,
+    /// Buffer right after creation.
+    pub buffer: Vec<Hex>,
+    /// Outputs of the first calls to `Balloon::step`.
+    pub steps: Vec<Hex>,
+}
+
+/// Start of the keystream of a `cipher::Stream`.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct KeystreamVector {
+    pub password: Hex,
+    pub salt: Hex,
+    pub settings: CryptSettings,
+    pub keystream: Hex,
+}
+
+/// Output of `cipher::encrypt`.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct EncryptionVector {
+    pub password: Hex,
+    pub header: Hex,
+    pub plaintext: Hex,
+    pub settings: CryptSettings,
+    /// Salts in the order they are drawn.
+    pub salts: Vec<Hex>,
+    /// Part of the ciphertext in front of the data, e.g. the encrypted MAC
+    /// for `Format::Legacy`.
+    pub prefix: Hex,
+    pub ciphertext: Hex,
+    /// Encrypted salt returned by `cipher::encrypt`.
+    pub salt: Hex,
+}
+
+/// Container created by `container::create_container`.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct ContainerVector {
+    pub password: Hex,
+    pub plaintext: Hex,
+    pub settings: CryptSettings,
+    /// Salts in the order they are drawn.
+    pub salts: Vec<Hex>,
+    pub container: Hex,
+}
+
+/// Collection of all test vectors.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct Corpus {
+    pub balloon: Vec<BalloonVector>,
+    pub keystream: Vec<KeystreamVector>,
+    pub encryption: Vec<EncryptionVector>,
+    pub containers: Vec<ContainerVector>,
+}
+
+/// Salt number `n` used by generated vectors.
+fn fixed_salt(n: u8) -> [u8; 64] {
+    let mut res = [0_u8; 64];
+    for (i, byte) in res.iter_mut().enumerate() {
+        *byte = n.wrapping_mul(64).wrapping_add(i as u8);
+    }
+    res
+}
+
+/// Small settings so that the corpus stays readable.
+fn settings(format: Format, revision: Revision) -> CryptSettings {
+    CryptSettings {
+        s_cost: 16,
+        format,
+        revision,
+        ..CryptSettings::default_for_testing()
+    }
+}
+
+/// Returns a closure handing out `salts` in order, followed by zeros.
+fn salt_source(salts: &[Hex]) -> Result<impl FnMut() -> [u8; 64]> {
+    let mut salts = salts
+        .iter()
+        .map(|salt| salt.0.as_slice().try_into())
+        .collect::<std::result::Result<Vec<[u8; 64]>, _>>()
+        .map_err(|_| Error::InvalidSettings("Salts must be 64 bytes long".to_string()))?
+        .into_iter();
+    Ok(move || salts.next().unwrap_or([0; 64]))
+}
+
+/// Length of the part of the ciphertext in front of the data.
+fn prefix_len(format: Format) -> usize {
+    match format {
+        Format::Legacy => 64,
+        Format::AuthenticatedHeader => 3 * 64,
+        Format::EncryptThenMac | Format::SinglePass | Format::Chunked => cipher::ETM_PREFIX_LEN,
+    }
+}
+
+impl BalloonVector {
+    fn new(
+        password: &[u8],
+        salt: &[u8],
+        s_cost: u64,
+        t_cost: u64,
+        step_delta: u64,
+        variant: Variant,
+    ) -> Self {
+        Self {
+            password: password.into(),
+            salt: salt.into(),
+            s_cost,
+            t_cost,
+            step_delta,
+            revision: variant.revision,
+            indexing: variant.indexing,
+            p_cost: variant.p_cost as u64,
+            hash: HashFunction::Sha3_512,
+            buffer: Vec::new(),
+            steps: Vec::new(),
+        }
+    }
+
+    /// Fills in the outputs from the inputs.
+    fn compute(&self) -> Result<Self> {
+        match self.hash {
+            HashFunction::Sha3_512 => self.compute_with::<sha3::Sha3_512>(),
+            HashFunction::Blake2b512 => self.compute_with::<blake2::Blake2b512>(),
+            HashFunction::Sha512 => self.compute_with::<sha2::Sha512>(),
+        }
+    }
+
+    fn compute_with<D: BalloonHash>(&self) -> Result<Self> {
+        let variant = Variant {
+            revision: self.revision,
+            indexing: self.indexing,
+            p_cost: cipher::to_usize(self.p_cost, "p_cost")?,
+        };
+        let mut balloon = Balloon::<D>::with_hash(
+            &self.password.0,
+            self.salt.0.clone(),
+            cipher::to_usize(self.s_cost, "s_cost")?,
+            cipher::to_usize(self.t_cost, "t_cost")?,
+            cipher::to_usize(self.step_delta, "step_delta")?,
+            variant,
+            Progress::new(),
+        )?;
+        let buffer = balloon.buffer().iter().map(|b| b[..].into()).collect();
+        let steps = (0..4)
+            .map(|_| balloon.step(Progress::new())[..].into())
+            .collect();
+        Ok(Self {
+            buffer,
+            steps,
+            ..self.clone()
+        })
+    }
+}
+
+impl KeystreamVector {
+    fn new(password: &[u8], salt: &[u8], settings: CryptSettings) -> Self {
+        Self {
+            password: password.into(),
+            salt: salt.into(),
+            settings,
+            keystream: Hex(Vec::new()),
+        }
+    }
+
+    fn compute(&self) -> Result<Self> {
+        let keystream = cipher::keystream(
+            &self.password.0,
+            self.salt.0.clone(),
+            self.settings,
+            4 * 64,
+            Progress::new(),
+        )?;
+        Ok(Self {
+            keystream: Hex(keystream),
+            ..self.clone()
+        })
+    }
+}
+
+impl EncryptionVector {
+    fn new(password: &[u8], header: &[u8], plaintext: &[u8], settings: CryptSettings) -> Self {
+        Self {
+            password: password.into(),
+            header: header.into(),
+            plaintext: plaintext.into(),
+            settings,
+            salts: vec![fixed_salt(1)[..].into(), fixed_salt(2)[..].into()],
+            prefix: Hex(Vec::new()),
+            ciphertext: Hex(Vec::new()),
+            salt: Hex(Vec::new()),
+        }
+    }
+
+    fn compute(&self) -> Result<Self> {
+        let mut ciphertext = Vec::new();
+        let salt = cipher::encrypt_with_salts(
+            &mut Cursor::new(&self.plaintext.0),
+            &mut ciphertext,
+            &self.password.0,
+            &self.header.0,
+            self.settings,
+            &mut salt_source(&self.salts)?,
+            false,
+            Progress::new(),
+        )?;
+        let prefix = ciphertext[..prefix_len(self.settings.format)].into();
+        Ok(Self {
+            prefix,
+            ciphertext: Hex(ciphertext),
+            salt: salt[..].into(),
+            ..self.clone()
+        })
+    }
+}
+
+impl ContainerVector {
+    fn new(password: &[u8], plaintext: &[u8], settings: CryptSettings) -> Self {
+        Self {
+            password: password.into(),
+            plaintext: plaintext.into(),
+            settings,
+            salts: vec![fixed_salt(3)[..].into(), fixed_salt(4)[..].into()],
+            container: Hex(Vec::new()),
+        }
+    }
+
+    fn compute(&self) -> Result<Self> {
+        let mut res = Cursor::new(Vec::new());
+        container::create_container_with_salts(
+            &mut Cursor::new(&self.plaintext.0),
+            &mut res,
+            &self.password.0,
+            self.settings,
+            &mut salt_source(&self.salts)?,
+            false,
+            None,
+        )?;
+        Ok(Self {
+            container: Hex(res.into_inner()),
+            ..self.clone()
+        })
+    }
+
+    /// Containers record the version of the crate that created them,
+    /// so instead of recreating them they are decrypted.
+    fn check(&self) -> Result<bool> {
+        let mut plaintext = Vec::new();
+        container::read_container(
+            &mut Cursor::new(&self.container.0),
+            &mut plaintext,
+            &self.password.0,
+            DecryptPolicy::default(),
+            None,
+        )?;
+        Ok(plaintext == self.plaintext.0)
+    }
+}
+
+impl Corpus {
+    /// Generates the corpus from fixed inputs.
+    pub fn generate() -> Result<Self> {
+        let formats = [
+            Format::Legacy,
+            Format::AuthenticatedHeader,
+            Format::EncryptThenMac,
+        ];
+
+        // Vectors are only ever appended, so that indices stay stable
+        let mut balloon = Vec::new();
+        let mut keystream = Vec::new();
+        let mut encryption = Vec::new();
+        let mut containers = Vec::new();
+        for revision in [Revision::V1, Revision::V2] {
+            let variant = Variant {
+                revision,
+                indexing: Indexing::Independent,
+                p_cost: 1,
+            };
+            balloon.extend([
+                BalloonVector::new(b"password", &[1, 2, 3], 16, 1, 1, variant),
+                BalloonVector::new(b"password", &fixed_salt(0), 16, 2, 3, variant),
+                BalloonVector::new(b"", &[], 1, 1, 1, variant),
+            ]);
+            keystream.extend([
+                KeystreamVector::new(
+                    b"password",
+                    &fixed_salt(0),
+                    settings(Format::EncryptThenMac, revision),
+                ),
+                KeystreamVector::new(b"", &[], settings(Format::EncryptThenMac, revision)),
+            ]);
+            // Older formats are only used together with older revisions
+            let formats = match revision {
+                Revision::V1 => &formats[..],
+                _ => &formats[2..],
+            };
+            for &format in formats {
+                encryption.push(EncryptionVector::new(
+                    b"password",
+                    b"header",
+                    b"Secret message",
+                    settings(format, revision),
+                ));
+                encryption.push(EncryptionVector::new(
+                    b"password",
+                    b"",
+                    b"",
+                    settings(format, revision),
+                ));
+                containers.push(ContainerVector::new(
+                    b"password",
+                    b"Secret message",
+                    settings(format, revision),
+                ));
+            }
+        }
+        for indexing in [Indexing::Dependent, Indexing::Hybrid] {
+            let variant = Variant {
+                revision: Revision::V2,
+                indexing,
+                p_cost: 1,
+            };
+            let settings = CryptSettings {
+                indexing,
+                ..settings(Format::EncryptThenMac, Revision::V2)
+            };
+            balloon.push(BalloonVector::new(
+                b"password",
+                &fixed_salt(0),
+                16,
+                2,
+                3,
+                variant,
+            ));
+            keystream.push(KeystreamVector::new(b"password", &fixed_salt(0), settings));
+            encryption.push(EncryptionVector::new(
+                b"password",
+                b"header",
+                b"Secret message",
+                settings,
+            ));
+        }
+        for p_cost in [2, 4] {
+            let variant = Variant {
+                p_cost,
+                ..Variant::default()
+            };
+            let settings = CryptSettings {
+                p_cost: p_cost as u64,
+                ..settings(Format::EncryptThenMac, Revision::V2)
+            };
+            balloon.push(BalloonVector::new(
+                b"password",
+                &fixed_salt(0),
+                16,
+                2,
+                3,
+                variant,
+            ));
+            keystream.push(KeystreamVector::new(b"password", &fixed_salt(0), settings));
+            encryption.push(EncryptionVector::new(
+                b"password",
+                b"header",
+                b"Secret message",
+                settings,
+            ));
+        }
+        for hash in [HashFunction::Blake2b512, HashFunction::Sha512] {
+            let settings = CryptSettings {
+                hash,
+                ..settings(Format::EncryptThenMac, Revision::V2)
+            };
+            balloon.push(BalloonVector {
+                hash,
+                ..BalloonVector::new(b"password", &fixed_salt(0), 16, 2, 3, Variant::default())
+            });
+            keystream.push(KeystreamVector::new(b"password", &fixed_salt(0), settings));
+            encryption.push(EncryptionVector::new(
+                b"password",
+                b"header",
+                b"Secret message",
+                settings,
+            ));
+        }
+
+        let settings = CryptSettings {
+            mode: KeystreamMode::Counter,
+            ..settings(Format::EncryptThenMac, Revision::V2)
+        };
+        keystream.push(KeystreamVector::new(b"password", &fixed_salt(0), settings));
+        encryption.push(EncryptionVector::new(
+            b"password",
+            b"header",
+            b"Secret message",
+            settings,
+        ));
+
+        // Containers are written without seeking since data descriptors were introduced
+        containers.push(ContainerVector::new(
+            b"password",
+            b"Secret message",
+            settings,
+        ));
+
+        let settings = CryptSettings {
+            format: Format::SinglePass,
+            mode: KeystreamMode::Sequential,
+            ..settings
+        };
+        encryption.push(EncryptionVector::new(
+            b"password",
+            b"header",
+            b"Secret message",
+            settings,
+        ));
+        containers.push(ContainerVector::new(
+            b"password",
+            b"Secret message",
+            settings,
+        ));
+
+        let settings = CryptSettings {
+            format: Format::Chunked,
+            mode: KeystreamMode::Counter,
+            ..settings
+        };
+        encryption.push(EncryptionVector::new(
+            b"password",
+            b"header",
+            b"Secret message",
+            settings,
+        ));
+        containers.push(ContainerVector::new(
+            b"password",
+            b"Secret message",
+            settings,
+        ));
+
+        // The data key and the salt it is wrapped with are drawn first
+        let mut vector =
+            ContainerVector::new(b"password", b"Secret message", settings.with_wrapped_key());
+        vector.salts = [5, 6, 3, 4].map(|i| fixed_salt(i)[..].into()).to_vec();
+        containers.push(vector);
+
+        Ok(Self {
+            balloon: balloon.iter().map(|v| v.compute()).collect::<Result<_>>()?,
+            keystream: keystream
+                .iter()
+                .map(|v| v.compute())
+                .collect::<Result<_>>()?,
+            encryption: encryption
+                .iter()
+                .map(|v| v.compute())
+                .collect::<Result<_>>()?,
+            containers: containers
+                .iter()
+                .map(|v| v.compute())
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Recomputes every vector from its inputs and compares the outputs.
+    /// Returns `Error::TestVectorMismatch` naming the first vector that differs.
+    pub fn verify(&self) -> Result<()> {
+        fn check(name: &str, i: usize, matches: bool) -> Result<()> {
+            match matches {
+                true => Ok(()),
+                false => Err(Error::TestVectorMismatch(format!("{name}[{i}]"))),
+            }
+        }
+
+        for (i, vector) in self.balloon.iter().enumerate() {
+            check("balloon", i, vector.compute()? == *vector)?;
+        }
+        for (i, vector) in self.keystream.iter().enumerate() {
+            check("keystream", i, vector.compute()? == *vector)?;
+        }
+        for (i, vector) in self.encryption.iter().enumerate() {
+            check("encryption", i, vector.compute()? == *vector)?;
+        }
+        for (i, vector) in self.containers.iter().enumerate() {
+            check("containers", i, vector.check()?)?;
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        match serde_json::from_str(json) {
+            Ok(corpus) => Ok(corpus),
+            Err(e) => Err(Error::InvalidSettings(format!("Invalid test vectors: {e}"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Published corpus, regenerate with `cargo run --example test_vectors`.
+    const CORPUS: &str = include_str!("../test_vectors/corpus.json");
+
+    #[test]
+    fn published_corpus_matches() {
+        Corpus::from_json(CORPUS).unwrap().verify().unwrap();
+    }
+
+    #[test]
+    fn tampered_vector_detected() {
+        let mut corpus = Corpus::from_json(CORPUS).unwrap();
+        corpus.encryption[1].ciphertext.0[0] ^= 1;
+        let err = corpus.verify().unwrap_err();
+        assert!(matches!(err, Error::TestVectorMismatch(name) if name == "encryption[1]"));
+    }
+
+    #[test]
+    fn hex_roundtrip() {
+        let hex = Hex(vec![0x00, 0xab, 0xff]);
+        let json = serde_json::to_string(&hex).unwrap();
+        assert_eq!(json, r#""00abff""#);
+        assert_eq!(serde_json::from_str::<Hex>(&json).unwrap(), hex);
+        assert!(serde_json::from_str::<Hex>(r#""abc""#).is_err());
+    }
+}